@@ -0,0 +1,325 @@
+use crate::codec::{Codec, CodecPreference};
+use http::{HeaderMap, header};
+
+/// Quality value meaning "fully acceptable", in thousandths.
+const MAX_QUALITY: u16 = 1000;
+
+/// The outcome of negotiating a content coding with the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Negotiation {
+    /// The response should be compressed with the given codec.
+    Codec(Codec),
+    /// The response should be sent without a content coding.
+    Identity,
+    /// The client refuses every coding the server can produce, including
+    /// `identity`. A server may respond with `406 Not Acceptable`.
+    NotAcceptable,
+}
+
+impl Negotiation {
+    /// Returns the negotiated codec, if the response should be compressed.
+    pub fn codec(&self) -> Option<Codec> {
+        match self {
+            Negotiation::Codec(codec) => Some(*codec),
+            Negotiation::Identity | Negotiation::NotAcceptable => None,
+        }
+    }
+}
+
+/// A parsed `Accept-Encoding` request header, following RFC 9110 section 12.5.3.
+///
+/// Supports `*` wildcards, explicit `identity` preferences, `q=0` exclusions and
+/// additional parameters on each entry. Entries with an invalid coding or
+/// quality value are ignored.
+#[derive(Debug, Clone, Default)]
+pub struct AcceptEncoding<'a> {
+    entries: Vec<Entry<'a>>,
+}
+
+/// A single coding and its quality, in thousandths.
+#[derive(Debug, Clone, Copy)]
+struct Entry<'a> {
+    coding: &'a str,
+    quality: u16,
+}
+
+impl<'a> AcceptEncoding<'a> {
+    /// Parses an `Accept-Encoding` header value.
+    pub fn parse(header: &'a str) -> Self {
+        let mut accept = Self::default();
+        accept.extend(header);
+        accept
+    }
+
+    /// Parses all `Accept-Encoding` headers in the map, as if they were a
+    /// single comma-separated list.
+    ///
+    /// Header values that aren't valid UTF-8 are ignored.
+    pub fn from_headers(headers: &'a HeaderMap) -> Self {
+        let mut accept = Self::default();
+        for value in headers.get_all(header::ACCEPT_ENCODING) {
+            if let Ok(value) = value.to_str() {
+                accept.extend(value);
+            }
+        }
+        accept
+    }
+
+    /// Appends the entries of a header value.
+    fn extend(&mut self, header: &'a str) {
+        self.entries.extend(
+            header
+                .split(',')
+                .filter_map(|entry| parse_entry(entry.trim())),
+        );
+    }
+
+    /// Negotiates the best codec using the default codec preference.
+    pub fn negotiate(&self) -> Negotiation {
+        self.negotiate_with(&CodecPreference::default())
+    }
+
+    /// Negotiates the best codec among the enabled codecs.
+    ///
+    /// Higher quality values win; ties are broken by the server's order of
+    /// preference. A codec is preferred over `identity` unless the client
+    /// explicitly ranks `identity` strictly higher.
+    pub(crate) fn negotiate_with(&self, preference: &CodecPreference) -> Negotiation {
+        self.negotiate_among(preference.iter())
+    }
+
+    /// Negotiates the best codec among the given codecs, which are in order
+    /// of preference.
+    pub(crate) fn negotiate_among(&self, codecs: impl IntoIterator<Item = Codec>) -> Negotiation {
+        let mut best: Option<(Codec, u16)> = None;
+        for codec in codecs {
+            let quality = self.codec_quality(codec);
+            if quality > 0 && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+                best = Some((codec, quality));
+            }
+        }
+
+        // Identity is acceptable unless excluded, explicitly or through `*`.
+        // When it isn't mentioned at all it never outranks a listed codec.
+        let identity = self
+            .explicit_quality(|coding| coding.eq_ignore_ascii_case("identity"))
+            .or_else(|| self.wildcard_quality());
+
+        match best {
+            Some((codec, quality)) if identity.is_none_or(|identity| quality >= identity) => {
+                Negotiation::Codec(codec)
+            }
+            _ if identity != Some(0) => Negotiation::Identity,
+            _ => Negotiation::NotAcceptable,
+        }
+    }
+
+    /// Checks if the codec is acceptable, explicitly or through `*`.
+    pub(crate) fn accepts_codec(&self, codec: Codec) -> bool {
+        self.codec_quality(codec) > 0
+    }
+
+    /// Returns the quality given to the codec, explicitly or through `*`.
+    fn codec_quality(&self, codec: Codec) -> u16 {
+        self.explicit_quality(|coding| codec.matches_token(coding))
+            .or_else(|| self.wildcard_quality())
+            .unwrap_or(0)
+    }
+
+    /// Checks if the coding is explicitly listed with a non-zero quality.
+    pub(crate) fn accepts(&self, coding: &str) -> bool {
+        self.explicit_quality(|c| c.eq_ignore_ascii_case(coding))
+            .is_some_and(|quality| quality > 0)
+    }
+
+    /// Returns the highest quality given to a coding matching `matches`.
+    fn explicit_quality(&self, matches: impl Fn(&str) -> bool) -> Option<u16> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.coding != "*" && matches(entry.coding))
+            .map(|entry| entry.quality)
+            .max()
+    }
+
+    /// Returns the quality given to the `*` wildcard.
+    fn wildcard_quality(&self) -> Option<u16> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.coding == "*")
+            .map(|entry| entry.quality)
+            .max()
+    }
+}
+
+/// Parses an entry like `gzip`, `br;q=0.8` or `*;foo=bar;q=0`.
+fn parse_entry(entry: &str) -> Option<Entry<'_>> {
+    let mut params = entry.split(';');
+    let coding = params.next()?.trim();
+    if !is_token(coding) {
+        return None;
+    }
+
+    let mut quality = MAX_QUALITY;
+    for param in params {
+        let (name, value) = param.split_once('=')?;
+        if name.trim().eq_ignore_ascii_case("q") {
+            quality = parse_quality(value.trim())?;
+        }
+    }
+
+    Some(Entry { coding, quality })
+}
+
+/// Parses a quality value (`qvalue` in RFC 9110) into thousandths.
+///
+/// Valid values are `0` to `1` with at most three decimal places.
+fn parse_quality(value: &str) -> Option<u16> {
+    let (int, frac) = value.split_once('.').unwrap_or((value, ""));
+    if frac.len() > 3 || !frac.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let thousandths = frac
+        .bytes()
+        .chain(std::iter::repeat(b'0'))
+        .take(3)
+        .fold(0, |acc, b| acc * 10 + u16::from(b - b'0'));
+    match int {
+        "0" => Some(thousandths),
+        "1" if thousandths == 0 => Some(MAX_QUALITY),
+        _ => None,
+    }
+}
+
+/// Returns whether the value is a token, like a content coding.
+pub(crate) fn is_token(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(is_tchar)
+}
+
+/// Returns whether the byte is allowed in a token (`tchar` in RFC 9110).
+fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_quality() {
+        assert_eq!(parse_quality("1"), Some(1000));
+        assert_eq!(parse_quality("1.000"), Some(1000));
+        assert_eq!(parse_quality("0"), Some(0));
+        assert_eq!(parse_quality("0.5"), Some(500));
+        assert_eq!(parse_quality("0.125"), Some(125));
+        assert_eq!(parse_quality("0."), Some(0));
+        assert_eq!(parse_quality("1.001"), None);
+        assert_eq!(parse_quality("0.1234"), None);
+        assert_eq!(parse_quality("2"), None);
+        assert_eq!(parse_quality("-1"), None);
+        assert_eq!(parse_quality(""), None);
+        assert_eq!(parse_quality("0.a"), None);
+    }
+
+    #[test]
+    fn test_no_header_is_identity() {
+        assert_eq!(
+            AcceptEncoding::from_headers(&HeaderMap::new()).negotiate(),
+            Negotiation::Identity
+        );
+    }
+
+    #[test]
+    #[cfg(all(
+        feature = "zstd",
+        feature = "brotli",
+        feature = "gzip",
+        feature = "deflate"
+    ))]
+    fn test_conformance() {
+        use Negotiation::*;
+
+        let cases = [
+            // Empty header: only identity is acceptable
+            ("", Identity),
+            ("gzip", Codec(crate::Codec::Gzip)),
+            ("GZIP", Codec(crate::Codec::Gzip)),
+            ("x-gzip", Codec(crate::Codec::Gzip)),
+            ("gzip, deflate, br, zstd", Codec(crate::Codec::Zstd)),
+            ("gzip;q=1.0, br;q=0.5", Codec(crate::Codec::Gzip)),
+            ("gzip ; q=0.5 , br ; q=0.8", Codec(crate::Codec::Brotli)),
+            // Wildcards
+            ("*", Codec(crate::Codec::Zstd)),
+            ("*;q=0.5, gzip", Codec(crate::Codec::Gzip)),
+            ("*;q=0, gzip", Codec(crate::Codec::Gzip)),
+            ("gzip;q=0, *", Codec(crate::Codec::Zstd)),
+            ("zstd;q=0, br;q=0, *", Codec(crate::Codec::Gzip)),
+            // Exclusions
+            ("gzip;q=0", Identity),
+            ("gzip;q=0.000", Identity),
+            ("identity;q=0", NotAcceptable),
+            ("*;q=0", NotAcceptable),
+            ("*;q=0, identity", Identity),
+            ("identity;q=0, gzip", Codec(crate::Codec::Gzip)),
+            ("compress, identity;q=0", NotAcceptable),
+            // Identity preferences
+            ("identity", Identity),
+            ("gzip;q=0.5, identity", Identity),
+            ("gzip, identity;q=0.5", Codec(crate::Codec::Gzip)),
+            ("gzip, identity", Codec(crate::Codec::Gzip)),
+            ("gzip;q=0.1", Codec(crate::Codec::Gzip)),
+            ("*;q=0.5, identity;q=0.1", Codec(crate::Codec::Zstd)),
+            // Unknown codings
+            ("compress", Identity),
+            ("compress, gzip;q=0.1", Codec(crate::Codec::Gzip)),
+            // Multiple parameters
+            (
+                "gzip;foo=bar;q=0.5, br;q=0.4;baz=1",
+                Codec(crate::Codec::Gzip),
+            ),
+            ("gzip;Q=0.1, br", Codec(crate::Codec::Brotli)),
+            // Invalid entries are ignored
+            ("gzip;q=2, br", Codec(crate::Codec::Brotli)),
+            ("gzip;q=0.1234, br;q=0.1", Codec(crate::Codec::Brotli)),
+            ("gzip;q, deflate", Codec(crate::Codec::Deflate)),
+            ("gz(ip, deflate", Codec(crate::Codec::Deflate)),
+            (",,gzip,,", Codec(crate::Codec::Gzip)),
+        ];
+
+        for (header, expected) in cases {
+            assert_eq!(
+                AcceptEncoding::parse(header).negotiate(),
+                expected,
+                "Accept-Encoding: {header}"
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "zstd", feature = "gzip"))]
+    fn test_negotiate_with_preference() {
+        let mut preference = CodecPreference::default();
+        preference.prefer([crate::Codec::Gzip]);
+        assert_eq!(
+            AcceptEncoding::parse("*").negotiate_with(&preference),
+            Negotiation::Codec(crate::Codec::Gzip)
+        );
+
+        preference.disable(crate::Codec::Gzip);
+        assert_eq!(
+            AcceptEncoding::parse("gzip, identity;q=0").negotiate_with(&preference),
+            Negotiation::NotAcceptable
+        );
+    }
+
+    #[test]
+    fn test_from_headers_multiple_lines() {
+        let mut headers = HeaderMap::new();
+        headers.append(header::ACCEPT_ENCODING, "identity;q=0".parse().unwrap());
+        headers.append(header::ACCEPT_ENCODING, "*;q=0".parse().unwrap());
+        assert_eq!(
+            AcceptEncoding::from_headers(&headers).negotiate(),
+            Negotiation::NotAcceptable
+        );
+    }
+}
@@ -0,0 +1,67 @@
+//! Writes precompressed `.zst`, `.br` and `.gz` siblings of static assets.
+//!
+//! Usage: `precompress [--level fastest|default|best|<n>] [--min-size <bytes>] <dir>...`
+
+use http_response_compression::{CompressionLayer, CompressionLevel, precompress_dir};
+use std::process::ExitCode;
+
+const USAGE: &str =
+    "usage: precompress [--level fastest|default|best|<n>] [--min-size <bytes>] <dir>...";
+
+fn main() -> ExitCode {
+    let mut layer = CompressionLayer::new();
+    let mut dirs = Vec::new();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--level" => match args.next().as_deref().and_then(parse_level) {
+                Some(level) => layer = layer.level(level),
+                None => return usage(),
+            },
+            "--min-size" => match args.next().and_then(|size| size.parse().ok()) {
+                Some(size) => layer = layer.min_size(size),
+                None => return usage(),
+            },
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return ExitCode::SUCCESS;
+            }
+            _ if arg.starts_with('-') => return usage(),
+            _ => dirs.push(arg),
+        }
+    }
+    if dirs.is_empty() {
+        return usage();
+    }
+
+    for dir in dirs {
+        match precompress_dir(&dir, &layer) {
+            Ok(written) => {
+                for path in written {
+                    println!("{}", path.display());
+                }
+            }
+            Err(e) => {
+                eprintln!("precompress: {dir}: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// Parses a compression level name or number.
+fn parse_level(level: &str) -> Option<CompressionLevel> {
+    match level {
+        "fastest" => Some(CompressionLevel::Fastest),
+        "default" => Some(CompressionLevel::Default),
+        "best" => Some(CompressionLevel::Best),
+        _ => level.parse().ok().map(CompressionLevel::Precise),
+    }
+}
+
+fn usage() -> ExitCode {
+    eprintln!("{USAGE}");
+    ExitCode::from(2)
+}
@@ -1,4 +1,6 @@
-use crate::codec::Codec;
+use crate::cache::Recorder;
+use crate::codec::{Codec, CompressionLevel};
+use crate::metrics::Observation;
 use bytes::{Buf, Bytes, BytesMut};
 use compression_codecs::EncodeV2;
 use compression_core::util::{PartialBuffer, WriteBuffer};
@@ -6,7 +8,8 @@ use http_body::{Body, Frame};
 use pin_project_lite::pin_project;
 use std::io;
 use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::task::{Context, Poll, ready};
+use std::time::Instant;
 
 const OUTPUT_BUFFER_SIZE: usize = 8 * 1024; // 8KB output buffer
 
@@ -15,9 +18,16 @@ pin_project! {
     ///
     /// This type wraps an inner body and either compresses it using the
     /// specified codec or passes it through unchanged.
-    #[project = CompressionBodyProj]
-    #[allow(missing_docs)]
-    pub enum CompressionBody<B> {
+    pub struct CompressionBody<B> {
+        #[pin]
+        pub(crate) kind: Kind<B>,
+    }
+}
+
+pin_project! {
+    /// How a [`CompressionBody`] produces its data.
+    #[project = KindProj]
+    pub(crate) enum Kind<B> {
         /// Compressed body with encoder.
         Compressed {
             #[pin]
@@ -29,6 +39,26 @@ pin_project! {
             #[pin]
             inner: B,
         },
+        /// Body whose beginning was buffered before deciding whether to
+        /// compress it, compressed if `state` is set. Bodies served from a
+        /// cache are buffered entirely.
+        Buffered {
+            #[pin]
+            inner: Replay<B>,
+            state: Option<CompressedBody>,
+        },
+    }
+}
+
+pin_project! {
+    /// A body that first replays frames read ahead of time, then continues
+    /// with the rest of the inner body.
+    pub(crate) struct Replay<B> {
+        prefix: Bytes,
+        trailers: Option<http::HeaderMap>,
+        error: Option<io::Error>,
+        // `None` once the inner body has ended
+        inner: Option<Pin<Box<B>>>,
     }
 }
 
@@ -39,6 +69,10 @@ pub(crate) struct CompressedBody {
     always_flush: bool,
     state: CompressState,
     pending_trailers: Option<http::HeaderMap>,
+    recorder: Option<Recorder>,
+    // Compressed after the inner body's data
+    padding: Option<Bytes>,
+    observation: Option<Observation>,
 }
 
 /// State machine for compression.
@@ -55,14 +89,17 @@ pub(crate) enum CompressState {
 }
 
 impl CompressedBody {
-    /// Creates a new compressed body state with the given codec.
-    fn new(codec: Codec, always_flush: bool) -> Self {
+    /// Creates a new compressed body state with the given encoder.
+    fn new(encoder: Box<dyn EncodeV2 + Send>, always_flush: bool) -> Self {
         Self {
-            encoder: codec.encoder(),
+            encoder,
             output_buffer: vec![0u8; OUTPUT_BUFFER_SIZE],
             always_flush,
             state: CompressState::Reading,
             pending_trailers: None,
+            recorder: None,
+            padding: None,
+            observation: None,
         }
     }
 
@@ -72,13 +109,66 @@ impl CompressedBody {
     }
 
     /// Returns whether always flush is enabled.
-    #[allow(dead_code)]
+    #[cfg(all(test, feature = "gzip"))]
     pub(crate) fn always_flush(&self) -> bool {
         self.always_flush
     }
 
-    /// Polls the inner body and compresses data.
+    /// Polls the inner body and compresses data, recording the output if a
+    /// recorder is set.
     fn poll_compressed<B>(
+        &mut self,
+        cx: &mut Context<'_>,
+        inner: Pin<&mut B>,
+    ) -> Poll<Option<Result<Frame<Bytes>, io::Error>>>
+    where
+        B: Body,
+        B::Data: Buf,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let result = ready!(self.poll_encoded(cx, inner));
+        if let Some(recorder) = &mut self.recorder {
+            match &result {
+                Some(Ok(frame)) => {
+                    // Bodies with trailers are only partially described by their data
+                    let recorded = frame.data_ref().is_some_and(|data| recorder.push(data));
+                    if !recorded {
+                        self.recorder = None;
+                    }
+                }
+                Some(Err(_)) => self.recorder = None,
+                None => {}
+            }
+        }
+        // Callers stop polling once `is_end_stream` is true, which may be
+        // before the body returns `None`
+        if self.state == CompressState::Done {
+            if let Some(recorder) = self.recorder.take() {
+                recorder.finish();
+            }
+            if let Some(observation) = self.observation.take() {
+                observation.report(true);
+            }
+        }
+        Poll::Ready(result)
+    }
+
+    /// Adds an encoder call that started at `start` to the statistics, if the
+    /// body is observed.
+    fn observe(&mut self, start: Option<Instant>, input: usize, output: usize, flushes: u64) {
+        if let Some(observation) = &mut self.observation
+            && let Some(start) = start
+        {
+            let stats = &mut observation.stats;
+            stats.input_bytes += input as u64;
+            stats.output_bytes += output as u64;
+            stats.flushes += flushes;
+            stats.encode_time += start.elapsed();
+        }
+    }
+
+    /// Polls the inner body and compresses data.
+    fn poll_encoded<B>(
         &mut self,
         cx: &mut Context<'_>,
         mut inner: Pin<&mut B>,
@@ -104,13 +194,23 @@ impl CompressedBody {
                 }
 
                 CompressState::Finishing => {
+                    if let Some(padding) = self.padding.take() {
+                        match self.compress_chunk(&padding) {
+                            Ok(Some(output)) => return Poll::Ready(Some(Ok(Frame::data(output)))),
+                            Ok(None) => continue,
+                            Err(e) => return Poll::Ready(Some(Err(e))),
+                        }
+                    }
+
                     // Finish the encoder
+                    let start = self.observation.is_some().then(Instant::now);
                     let mut output =
                         WriteBuffer::new_initialized(self.output_buffer.as_mut_slice());
 
                     match self.encoder.finish(&mut output) {
                         Ok(done) => {
                             let written = output.written_len();
+                            self.observe(start, 0, written, 0);
                             if written > 0 {
                                 let data = Bytes::copy_from_slice(&self.output_buffer[..written]);
                                 if done {
@@ -154,7 +254,17 @@ impl CompressedBody {
                                 Ok(mut data) => {
                                     // Compress the data
                                     let input_bytes = data.copy_to_bytes(data.remaining());
-                                    return self.compress_chunk(&input_bytes);
+                                    match self.compress_chunk(&input_bytes) {
+                                        Ok(Some(output)) => {
+                                            return Poll::Ready(Some(Ok(Frame::data(output))));
+                                        }
+                                        // The encoder buffered the input without producing
+                                        // output. The inner body was ready, so no wakeup is
+                                        // registered: poll it again rather than returning
+                                        // Pending.
+                                        Ok(None) => continue,
+                                        Err(e) => return Poll::Ready(Some(Err(e))),
+                                    }
                                 }
                                 Err(frame) => {
                                     if let Ok(trailers) = frame.into_trailers() {
@@ -173,7 +283,11 @@ impl CompressedBody {
     }
 
     /// Compresses a chunk of input data.
-    fn compress_chunk(&mut self, input: &[u8]) -> Poll<Option<Result<Frame<Bytes>, io::Error>>> {
+    ///
+    /// Returns `None` if the encoder buffered the input without producing any
+    /// output yet.
+    fn compress_chunk(&mut self, input: &[u8]) -> io::Result<Option<Bytes>> {
+        let start = self.observation.is_some().then(Instant::now);
         let mut input_buf = PartialBuffer::new(input);
         let mut all_output = BytesMut::new();
 
@@ -181,9 +295,9 @@ impl CompressedBody {
         loop {
             let mut output = WriteBuffer::new_initialized(self.output_buffer.as_mut_slice());
 
-            if let Err(e) = self.encoder.encode(&mut input_buf, &mut output) {
-                return Poll::Ready(Some(Err(io::Error::other(e))));
-            }
+            self.encoder
+                .encode(&mut input_buf, &mut output)
+                .map_err(io::Error::other)?;
 
             let written = output.written_len();
             if written > 0 {
@@ -206,28 +320,120 @@ impl CompressedBody {
             loop {
                 let mut output = WriteBuffer::new_initialized(self.output_buffer.as_mut_slice());
 
-                match self.encoder.flush(&mut output) {
-                    Ok(done) => {
-                        let written = output.written_len();
-                        if written > 0 {
-                            all_output.extend_from_slice(&self.output_buffer[..written]);
-                        }
-                        if done {
-                            break;
-                        }
-                    }
-                    Err(e) => {
-                        return Poll::Ready(Some(Err(io::Error::other(e))));
-                    }
+                let done = self.encoder.flush(&mut output).map_err(io::Error::other)?;
+                let written = output.written_len();
+                if written > 0 {
+                    all_output.extend_from_slice(&self.output_buffer[..written]);
+                }
+                if done {
+                    break;
                 }
             }
         }
 
+        let flushes = u64::from(self.always_flush);
+        self.observe(start, input.len(), all_output.len(), flushes);
+
         if all_output.is_empty() {
-            // No output yet, need to continue polling
-            Poll::Pending
+            Ok(None)
         } else {
-            Poll::Ready(Some(Ok(Frame::data(all_output.freeze()))))
+            Ok(Some(all_output.freeze()))
+        }
+    }
+}
+
+/// Reports bodies dropped before they were completely compressed.
+impl Drop for CompressedBody {
+    fn drop(&mut self) {
+        if let Some(observation) = self.observation.take() {
+            observation.report(false);
+        }
+    }
+}
+
+impl<B> Replay<B> {
+    /// Creates a body replaying `prefix`, followed by the rest of `inner`.
+    pub(crate) fn new(prefix: Bytes, inner: Pin<Box<B>>) -> Self {
+        Self {
+            prefix,
+            trailers: None,
+            error: None,
+            inner: Some(inner),
+        }
+    }
+
+    /// Creates a body replaying `prefix`, followed by the trailers or error
+    /// that ended the inner body.
+    pub(crate) fn ended(
+        prefix: Bytes,
+        trailers: Option<http::HeaderMap>,
+        error: Option<io::Error>,
+    ) -> Self {
+        Self {
+            prefix,
+            trailers,
+            error,
+            inner: None,
+        }
+    }
+}
+
+impl<B> Body for Replay<B>
+where
+    B: Body,
+    B::Data: Buf,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        if !this.prefix.is_empty() {
+            return Poll::Ready(Some(Ok(Frame::data(std::mem::take(this.prefix)))));
+        }
+        if let Some(error) = this.error.take() {
+            return Poll::Ready(Some(Err(error)));
+        }
+        if let Some(trailers) = this.trailers.take() {
+            return Poll::Ready(Some(Ok(Frame::trailers(trailers))));
+        }
+        match this.inner {
+            Some(inner) => inner
+                .as_mut()
+                .poll_frame(cx)
+                .map_ok(|frame| frame.map_data(|mut data| data.copy_to_bytes(data.remaining())))
+                .map_err(|e| io::Error::other(e.into())),
+            None => Poll::Ready(None),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.prefix.is_empty()
+            && self.trailers.is_none()
+            && self.error.is_none()
+            && self
+                .inner
+                .as_ref()
+                .is_none_or(|inner| inner.is_end_stream())
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        let prefix = self.prefix.len() as u64;
+        match &self.inner {
+            Some(inner) => {
+                let inner = inner.size_hint();
+                let mut hint = http_body::SizeHint::new();
+                hint.set_lower(inner.lower() + prefix);
+                if let Some(upper) = inner.upper() {
+                    hint.set_upper(upper + prefix);
+                }
+                hint
+            }
+            None => http_body::SizeHint::with_exact(prefix),
         }
     }
 }
@@ -235,15 +441,96 @@ impl CompressedBody {
 impl<B> CompressionBody<B> {
     /// Creates a compressed body with the given codec.
     pub fn compressed(inner: B, codec: Codec, always_flush: bool) -> Self {
-        Self::Compressed {
+        Self::compressed_with_level(inner, codec, CompressionLevel::Default, always_flush)
+    }
+
+    /// Creates a compressed body with the given codec and compression level.
+    pub fn compressed_with_level(
+        inner: B,
+        codec: Codec,
+        level: CompressionLevel,
+        always_flush: bool,
+    ) -> Self {
+        Self::new(Kind::Compressed {
             inner,
-            state: CompressedBody::new(codec, always_flush),
-        }
+            state: CompressedBody::new(codec.encoder(level), always_flush),
+        })
+    }
+
+    /// Creates a body compressed with the given encoder.
+    pub(crate) fn encoded(inner: B, encoder: Box<dyn EncodeV2 + Send>, always_flush: bool) -> Self {
+        Self::new(Kind::Compressed {
+            inner,
+            state: CompressedBody::new(encoder, always_flush),
+        })
     }
 
     /// Creates a passthrough body without compression.
     pub fn passthrough(inner: B) -> Self {
-        Self::Passthrough { inner }
+        Self::new(Kind::Passthrough { inner })
+    }
+
+    /// Creates a body from previously compressed data.
+    pub(crate) fn cached(data: Bytes) -> Self {
+        Self::buffered(Replay::ended(data, None, None), None)
+    }
+
+    fn new(kind: Kind<B>) -> Self {
+        Self { kind }
+    }
+
+    /// Returns the compression state of this body, if it is compressed.
+    fn compressed_state(&mut self) -> Option<&mut CompressedBody> {
+        match &mut self.kind {
+            Kind::Compressed { state, .. }
+            | Kind::Buffered {
+                state: Some(state), ..
+            } => Some(state),
+            _ => None,
+        }
+    }
+
+    /// Records the compressed output of this body, to be cached once it ends.
+    ///
+    /// Does nothing if the body isn't compressed.
+    pub(crate) fn record(&mut self, recorder: Recorder) {
+        if let Some(state) = self.compressed_state() {
+            state.recorder = Some(recorder);
+        }
+    }
+
+    /// Reports the statistics of this body to an observer once it ends or is
+    /// dropped.
+    ///
+    /// Does nothing if the body isn't compressed.
+    pub(crate) fn observe(&mut self, observation: Observation) {
+        if let Some(state) = self.compressed_state() {
+            state.observation = Some(observation);
+        }
+    }
+
+    /// Appends padding to the data of this body before it is compressed.
+    ///
+    /// Does nothing if the body isn't compressed.
+    pub(crate) fn pad(&mut self, padding: Bytes) {
+        if let Some(state) = self.compressed_state() {
+            state.padding = Some(padding);
+        }
+    }
+}
+
+impl<B> CompressionBody<B> {
+    /// Creates a body from a buffered prefix and the rest of the inner body,
+    /// compressed with the given encoder if one is set.
+    pub(crate) fn buffered(
+        inner: Replay<B>,
+        encoder: Option<(Box<dyn EncodeV2 + Send>, bool)>,
+    ) -> Self {
+        Self::new(Kind::Buffered {
+            inner,
+            state: encoder
+                .map(|(encoder, always_flush)| CompressedBody::new(encoder, always_flush)),
+        })
     }
 }
 
@@ -260,8 +547,8 @@ where
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
-        match self.project() {
-            CompressionBodyProj::Passthrough { inner } => {
+        match self.project().kind.project() {
+            KindProj::Passthrough { inner } => {
                 // Pass through frames, converting data to Bytes
                 match inner.poll_frame(cx) {
                     Poll::Pending => Poll::Pending,
@@ -273,39 +560,61 @@ where
                     Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(io::Error::other(e.into())))),
                 }
             }
-            CompressionBodyProj::Compressed { inner, state } => state.poll_compressed(cx, inner),
+            KindProj::Compressed { inner, state } => state.poll_compressed(cx, inner),
+            KindProj::Buffered {
+                inner,
+                state: Some(state),
+            } => state.poll_compressed(cx, inner),
+            KindProj::Buffered { inner, state: None } => inner.poll_frame(cx),
         }
     }
 
     fn is_end_stream(&self) -> bool {
-        match self {
-            CompressionBody::Passthrough { inner } => inner.is_end_stream(),
-            CompressionBody::Compressed { state, .. } => state.state() == CompressState::Done,
+        match &self.kind {
+            Kind::Passthrough { inner } => inner.is_end_stream(),
+            Kind::Compressed { state, .. }
+            | Kind::Buffered {
+                state: Some(state), ..
+            } => state.state() == CompressState::Done,
+            Kind::Buffered { inner, state: None } => inner.is_end_stream(),
         }
     }
 
     fn size_hint(&self) -> http_body::SizeHint {
-        match self {
-            CompressionBody::Passthrough { inner } => inner.size_hint(),
+        match &self.kind {
+            Kind::Passthrough { inner } => inner.size_hint(),
+            Kind::Buffered { inner, state: None } => inner.size_hint(),
             // Compressed size is unknown
-            CompressionBody::Compressed { .. } => http_body::SizeHint::default(),
+            Kind::Compressed { .. } | Kind::Buffered { .. } => http_body::SizeHint::default(),
         }
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    use compression_codecs::DecodeV2;
     use http::HeaderMap;
     use std::collections::VecDeque;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Wake, Waker};
 
     /// A test body that yields predefined frames.
+    ///
+    /// A `None` entry makes the body return `Pending` once, after waking the
+    /// task like a real body would once more data arrives.
     struct TestBody {
-        frames: VecDeque<Frame<Bytes>>,
+        frames: VecDeque<Option<Frame<Bytes>>>,
     }
 
     impl TestBody {
         fn new(frames: Vec<Frame<Bytes>>) -> Self {
+            Self::with_pending(frames.into_iter().map(Some).collect())
+        }
+
+        fn with_pending(frames: Vec<Option<Frame<Bytes>>>) -> Self {
             Self {
                 frames: frames.into(),
             }
@@ -318,15 +627,90 @@ mod tests {
 
         fn poll_frame(
             mut self: Pin<&mut Self>,
-            _cx: &mut Context<'_>,
+            cx: &mut Context<'_>,
         ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
             match self.frames.pop_front() {
-                Some(frame) => Poll::Ready(Some(Ok(frame))),
+                Some(Some(frame)) => Poll::Ready(Some(Ok(frame))),
+                Some(None) => {
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
                 None => Poll::Ready(None),
             }
         }
     }
 
+    /// A waker that counts how many times it was woken.
+    #[derive(Default)]
+    struct CountingWaker {
+        wakes: AtomicUsize,
+    }
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.wake_by_ref();
+        }
+
+        fn wake_by_ref(self: &Arc<Self>) {
+            self.wakes.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Polls a body to completion, returning its data and trailers.
+    ///
+    /// Panics if the body returns `Pending` without arranging for the task to
+    /// be woken, since a real executor would never poll it again.
+    fn poll_to_end<B>(body: &mut B) -> (Vec<u8>, Option<HeaderMap>)
+    where
+        B: Body<Data = Bytes> + Unpin,
+        B::Error: std::fmt::Debug,
+    {
+        let counter = Arc::new(CountingWaker::default());
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut data = Vec::new();
+        let mut trailers = None;
+        loop {
+            let wakes = counter.wakes.load(Ordering::SeqCst);
+            match Pin::new(&mut *body).poll_frame(&mut cx) {
+                Poll::Pending => assert!(
+                    counter.wakes.load(Ordering::SeqCst) > wakes,
+                    "body returned Pending without a registered wakeup"
+                ),
+                Poll::Ready(None) => break,
+                Poll::Ready(Some(frame)) => match frame.unwrap().into_data() {
+                    Ok(chunk) => data.extend_from_slice(&chunk),
+                    Err(frame) => trailers = frame.into_trailers().ok(),
+                },
+            }
+        }
+        (data, trailers)
+    }
+
+    /// Decompresses a complete gzip stream.
+    #[cfg(feature = "gzip")]
+    pub(crate) fn gunzip(input: &[u8]) -> Vec<u8> {
+        decode(Codec::Gzip.decoder(), input)
+    }
+
+    /// Decompresses a complete stream with the decoder.
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    pub(crate) fn decode(mut decoder: Box<dyn DecodeV2 + Send>, input: &[u8]) -> Vec<u8> {
+        let mut input = PartialBuffer::new(input);
+        let mut buffer = vec![0u8; 1024];
+        let mut decoded = Vec::new();
+        loop {
+            let mut output = WriteBuffer::new_initialized(buffer.as_mut_slice());
+            let done = decoder.decode(&mut input, &mut output).unwrap();
+            let written = output.written_len();
+            decoded.extend_from_slice(&buffer[..written]);
+            if done {
+                return decoded;
+            }
+        }
+    }
+
     fn poll_body<B: Body + Unpin>(body: &mut B) -> Option<Result<Frame<B::Data>, B::Error>> {
         let waker = std::task::Waker::noop();
         let mut cx = Context::from_waker(waker);
@@ -372,6 +756,21 @@ mod tests {
         assert!(poll_body(&mut body).is_none());
     }
 
+    #[test]
+    fn test_passthrough_pending_inner_body() {
+        let inner = TestBody::with_pending(vec![
+            None,
+            Some(Frame::data(Bytes::from("hello "))),
+            None,
+            Some(Frame::data(Bytes::from("world"))),
+        ]);
+        let mut body = CompressionBody::passthrough(inner);
+
+        let (data, trailers) = poll_to_end(&mut body);
+        assert_eq!(data, b"hello world");
+        assert!(trailers.is_none());
+    }
+
     #[test]
     #[cfg(feature = "gzip")]
     fn test_compressed_produces_output() {
@@ -424,4 +823,88 @@ mod tests {
             .unwrap();
         assert_eq!(trailers.get("x-checksum").unwrap(), "abc123");
     }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_compressed_buffered_chunks_do_not_stall() {
+        // Small chunks are buffered by the encoder without producing output,
+        // while the inner body is always ready.
+        let chunks: Vec<_> = (0..100)
+            .map(|i| Frame::data(Bytes::from(format!("chunk {i} "))))
+            .collect();
+        let expected: String = (0..100).map(|i| format!("chunk {i} ")).collect();
+
+        let inner = TestBody::new(chunks);
+        let mut body = CompressionBody::compressed(inner, Codec::Gzip, false);
+
+        let (data, trailers) = poll_to_end(&mut body);
+        assert_eq!(gunzip(&data), expected.as_bytes());
+        assert!(trailers.is_none());
+        assert!(body.is_end_stream());
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_compressed_pending_inner_body() {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("x-checksum", "abc123".parse().unwrap());
+
+        let inner = TestBody::with_pending(vec![
+            None,
+            Some(Frame::data(Bytes::from("hello "))),
+            Some(Frame::data(Bytes::from("wonderful "))),
+            None,
+            Some(Frame::data(Bytes::from("world"))),
+            None,
+            Some(Frame::trailers(trailers)),
+        ]);
+        let mut body = CompressionBody::compressed(inner, Codec::Gzip, false);
+
+        let (data, trailers) = poll_to_end(&mut body);
+        assert_eq!(gunzip(&data), b"hello wonderful world");
+        assert_eq!(trailers.unwrap().get("x-checksum").unwrap(), "abc123");
+    }
+
+    /// Polls a body until it reports the end of the stream, like hyper does,
+    /// without waiting for it to return `None`.
+    #[cfg(feature = "gzip")]
+    fn poll_until_end_stream<B: Body + Unpin>(body: &mut B) {
+        while !body.is_end_stream() {
+            poll_body(body)
+                .expect("body ended")
+                .ok()
+                .expect("body error");
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_complete_at_end_stream() {
+        let cache = crate::CompressionCache::new(1024 * 1024);
+        let counters = crate::CompressionCounters::new();
+        let inner = TestBody::new(vec![Frame::data(Bytes::from("hello world"))]);
+        let mut body = CompressionBody::compressed(inner, Codec::Gzip, false);
+        body.record(cache.recorder("key".to_owned(), Codec::Gzip));
+        body.observe(Observation::new(Codec::Gzip, Some(Arc::new(counters.clone()))).unwrap());
+
+        poll_until_end_stream(&mut body);
+        assert_eq!(cache.len(), 1);
+        drop(body);
+        assert_eq!(counters.finished(), 1);
+        assert_eq!(counters.dropped(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_compressed_always_flush_emits_each_chunk() {
+        let inner = TestBody::with_pending(vec![
+            Some(Frame::data(Bytes::from("event: one\n\n"))),
+            None,
+            Some(Frame::data(Bytes::from("event: two\n\n"))),
+        ]);
+        let mut body = CompressionBody::compressed(inner, Codec::Gzip, true);
+
+        let (data, _) = poll_to_end(&mut body);
+        assert_eq!(gunzip(&data), b"event: one\n\nevent: two\n\n");
+    }
 }
@@ -0,0 +1,278 @@
+use crate::content_type::{ContentTypeFilter, parse_essence};
+use crate::predicate::{Predicate, RequestInfo, ResponseInfo};
+use bytes::Bytes;
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+
+/// Request header telling how the request's initiator relates to its target.
+const SEC_FETCH_SITE: &str = "sec-fetch-site";
+
+/// Characters used for padding, which are valid in headers and HTML comments.
+const PADDING_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Default maximum padding length, in bytes.
+const DEFAULT_MAX_PADDING: usize = 32;
+
+/// Largest maximum padding length, in bytes, which keeps padding headers
+/// within the limits of common servers and proxies.
+const MAX_PADDING: usize = 4096;
+
+/// Mitigations against BREACH, which recovers secrets from the compressed
+/// size of responses that also reflect attacker-controlled input.
+///
+/// By default, `text/html` responses to cross-site requests
+/// (`Sec-Fetch-Site: cross-site`) aren't compressed, as a BREACH attack
+/// makes the victim's browser send such requests. Compressed responses of
+/// those types get `Vary: Sec-Fetch-Site`. Compressed responses can also be
+/// padded with a random amount of data, so their size is less useful to an
+/// attacker.
+///
+/// Individual responses carrying secrets can opt out of compression with the
+/// [`NoCompression`] extension, with or without these mitigations.
+///
+/// ```ignore
+/// use http_response_compression::{BreachMitigation, CompressionLayer, ContentTypeFilter, Padding};
+///
+/// let layer = CompressionLayer::new().breach_mitigation(
+///     BreachMitigation::new()
+///         .padding(Padding::HtmlComment, 64)
+///         .cross_site_content_types(
+///             ContentTypeFilter::new()
+///                 .deny("text/html")
+///                 .deny("application/json"),
+///         ),
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct BreachMitigation {
+    padding: Option<Padding>,
+    max_padding: usize,
+    cross_site: ContentTypeFilter,
+}
+
+/// Where the random padding of a compressed response is added.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Padding {
+    /// Appends an HTML comment to `text/html` bodies before compressing them.
+    ///
+    /// Other content types aren't padded. Padded responses aren't cached, as
+    /// every response needs its own padding.
+    HtmlComment,
+    /// Adds a response header with a random value.
+    Header(HeaderName),
+}
+
+/// Response extension marking a response that must not be compressed, such
+/// as one mixing secrets with reflected input.
+///
+/// ```ignore
+/// use http_response_compression::NoCompression;
+///
+/// response.extensions_mut().insert(NoCompression);
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NoCompression;
+
+impl BreachMitigation {
+    /// Creates mitigations that don't compress `text/html` responses to
+    /// cross-site requests, without padding.
+    pub fn new() -> Self {
+        Self {
+            padding: None,
+            max_padding: DEFAULT_MAX_PADDING,
+            cross_site: ContentTypeFilter::new().deny("text/html"),
+        }
+    }
+
+    /// Pads compressed responses with up to `max_len` random bytes.
+    ///
+    /// `max_len` is capped at 4096 bytes.
+    pub fn padding(mut self, padding: Padding, max_len: usize) -> Self {
+        self.padding = Some(padding);
+        self.max_padding = max_len.min(MAX_PADDING);
+        self
+    }
+
+    /// Sets which content types are still compressed for cross-site requests.
+    ///
+    /// Defaults to every type but `text/html`. Use [`ContentTypeFilter::new`]
+    /// to compress every type regardless of the request's origin.
+    pub fn cross_site_content_types(mut self, filter: ContentTypeFilter) -> Self {
+        self.cross_site = filter;
+        self
+    }
+
+    /// Checks if the response is of a type that isn't compressed for
+    /// cross-site requests.
+    fn is_protected(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|content_type| !self.cross_site.is_compressible(content_type))
+    }
+
+    /// Checks if the body of the response is padded, which makes it uncacheable.
+    pub(crate) fn pads_body(&self, headers: &HeaderMap) -> bool {
+        self.padding == Some(Padding::HtmlComment) && is_html(headers)
+    }
+
+    /// Updates the headers of a response that is being compressed, returning
+    /// the padding to append to its body, if any.
+    pub(crate) fn apply(&self, headers: &mut HeaderMap) -> Option<Bytes> {
+        if self.is_protected(headers) {
+            crate::future::add_vary(headers, SEC_FETCH_SITE);
+        }
+        match &self.padding {
+            Some(Padding::HtmlComment) if is_html(headers) => {
+                Some(format!("<!-- {} -->", random_padding(self.max_padding)).into())
+            }
+            Some(Padding::Header(name)) => {
+                let value = HeaderValue::from_str(&random_padding(self.max_padding))
+                    .expect("padding is a valid header value");
+                headers.insert(name.clone(), value);
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Default for BreachMitigation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Skips compressing protected content types for cross-site requests.
+impl Predicate for BreachMitigation {
+    fn should_compress(&self, request: &RequestInfo, response: &ResponseInfo<'_>) -> bool {
+        let cross_site = request
+            .headers()
+            .get(SEC_FETCH_SITE)
+            .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"cross-site"));
+        !cross_site || !self.is_protected(response.headers())
+    }
+}
+
+/// Checks if the response is an HTML document.
+fn is_html(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_essence)
+        .is_some_and(|(ty, subtype)| {
+            ty.eq_ignore_ascii_case("text") && subtype.eq_ignore_ascii_case("html")
+        })
+}
+
+/// Returns up to `max_len` random padding characters.
+///
+/// Random characters, unlike a repeated one, don't compress away, so the
+/// compressed size varies along with the padding's length.
+///
+/// The padding comes from the operating system's random number generator, as
+/// an attacker able to predict it could subtract it from the compressed size.
+///
+/// # Panics
+///
+/// Panics if the operating system's random number generator fails.
+fn random_padding(max_len: usize) -> String {
+    let len = (random() % (max_len as u64 + 1)) as usize;
+    let mut bytes = vec![0; len];
+    getrandom::fill(&mut bytes).expect("random number generator failed");
+    bytes
+        .into_iter()
+        .map(|b| PADDING_ALPHABET[usize::from(b & 0x3f)] as char)
+        .collect()
+}
+
+/// Returns a random value from the operating system's random number
+/// generator.
+///
+/// # Panics
+///
+/// Panics if the operating system's random number generator fails.
+fn random() -> u64 {
+    getrandom::u64().expect("random number generator failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::{Request, response};
+
+    fn parts(content_type: &'static str) -> response::Parts {
+        let mut parts = http::Response::new(()).into_parts().0;
+        parts
+            .headers
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+        parts
+    }
+
+    fn request(site: &'static str) -> RequestInfo {
+        let request = Request::get("/")
+            .header(SEC_FETCH_SITE, site)
+            .body(())
+            .unwrap();
+        RequestInfo::from_request(&request)
+    }
+
+    #[test]
+    fn test_cross_site_rule() {
+        let mitigation = BreachMitigation::new();
+        let html = parts("text/html; charset=utf-8");
+        let json = parts("application/json");
+        let check = |site, parts: &response::Parts| {
+            let response = ResponseInfo::new(parts, http_body::SizeHint::new());
+            mitigation.should_compress(&request(site), &response)
+        };
+
+        assert!(!check("cross-site", &html));
+        assert!(check("same-origin", &html));
+        assert!(check("same-site", &html));
+        assert!(check("cross-site", &json));
+    }
+
+    #[test]
+    fn test_random_padding() {
+        let lengths: std::collections::HashSet<_> =
+            (0..100).map(|_| random_padding(32).len()).collect();
+        assert!(lengths.iter().all(|len| *len <= 32));
+        assert!(lengths.len() > 1);
+        assert!(
+            random_padding(1000)
+                .bytes()
+                .all(|b| PADDING_ALPHABET.contains(&b))
+        );
+        assert_eq!(random_padding(0), "");
+    }
+
+    #[test]
+    fn test_padding_capped() {
+        let mitigation = BreachMitigation::new().padding(Padding::HtmlComment, usize::MAX);
+        assert_eq!(mitigation.max_padding, MAX_PADDING);
+        let mut html = parts("text/html").headers;
+        let padding = mitigation.apply(&mut html).unwrap();
+        assert!(padding.len() <= MAX_PADDING + "<!--  -->".len());
+    }
+
+    #[test]
+    fn test_apply() {
+        let mitigation = BreachMitigation::new().padding(Padding::HtmlComment, 16);
+        let mut html = parts("text/html").headers;
+        let padding = mitigation.apply(&mut html).unwrap();
+        assert!(padding.starts_with(b"<!-- ") && padding.ends_with(b" -->"));
+        assert_eq!(html[header::VARY], "sec-fetch-site");
+
+        let mut json = parts("application/json").headers;
+        assert!(mitigation.apply(&mut json).is_none());
+        assert!(json.get(header::VARY).is_none());
+
+        let name = HeaderName::from_static("x-padding");
+        let mitigation = BreachMitigation::new().padding(Padding::Header(name.clone()), 16);
+        let mut json = parts("application/json").headers;
+        assert!(mitigation.apply(&mut json).is_none());
+        assert!(json[&name].len() <= 16);
+    }
+}
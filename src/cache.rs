@@ -0,0 +1,322 @@
+use crate::codec::Codec;
+use crate::predicate::{RequestInfo, ResponseInfo};
+use bytes::{Bytes, BytesMut};
+use http::header;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Computes the cache key of a response.
+type KeyFn = dyn Fn(&RequestInfo, &ResponseInfo<'_>) -> Option<String> + Send + Sync;
+
+/// An in-memory cache of compressed response bodies.
+///
+/// Set on a layer with [`CompressionLayer::cache`], the cache stores the
+/// complete output of every compressed response with a key, and serves later
+/// responses with the same key and codec from memory, with an exact
+/// `Content-Length`, instead of compressing their body again.
+///
+/// By default, responses are keyed by their request URI and strong `ETag`, so
+/// responses without one aren't cached; [`key_with`] supplies a custom key.
+/// The least recently used entries are evicted once the total size of the
+/// cached bodies exceeds the capacity. Bodies ending with trailers or an
+/// error, streaming responses and responses to `HEAD` requests are never
+/// cached.
+///
+/// Clones share the same entries, so one cache can be inspected or cleared
+/// while the layer uses it.
+///
+/// ```ignore
+/// use http_response_compression::{CompressionCache, CompressionLayer};
+///
+/// let cache = CompressionCache::new(64 * 1024 * 1024)
+///     .key_with(|req, _| Some(req.uri().path().to_owned()));
+/// let layer = CompressionLayer::new().cache(cache);
+/// ```
+///
+/// [`CompressionLayer::cache`]: crate::CompressionLayer::cache
+/// [`key_with`]: CompressionCache::key_with
+#[derive(Clone)]
+pub struct CompressionCache {
+    entries: Arc<Mutex<Lru>>,
+    key: Option<Arc<KeyFn>>,
+}
+
+impl CompressionCache {
+    /// Creates an empty cache holding up to `capacity` bytes of compressed
+    /// bodies.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(Lru::new(capacity))),
+            key: None,
+        }
+    }
+
+    /// Sets the function computing the cache key of a response.
+    ///
+    /// Responses for which it returns `None` aren't cached. Responses with
+    /// the same key must have the same body, as the compressed body of one is
+    /// served for the others.
+    pub fn key_with<F>(mut self, key: F) -> Self
+    where
+        F: Fn(&RequestInfo, &ResponseInfo<'_>) -> Option<String> + Send + Sync + 'static,
+    {
+        self.key = Some(Arc::new(key));
+        self
+    }
+
+    /// Returns the maximum total size of the cached bodies, in bytes.
+    pub fn capacity(&self) -> usize {
+        self.lock().capacity
+    }
+
+    /// Returns the total size of the cached bodies, in bytes.
+    pub fn size(&self) -> usize {
+        self.lock().size
+    }
+
+    /// Returns the number of cached bodies.
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    /// Checks if the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes every cached body.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    /// Returns the cache key of a response, if it can be cached.
+    pub(crate) fn key(&self, request: &RequestInfo, response: &ResponseInfo<'_>) -> Option<String> {
+        match &self.key {
+            Some(key) => key(request, response),
+            None => {
+                let etag = response.headers().get(header::ETAG)?.to_str().ok()?;
+                // Weak validators don't guarantee identical bodies
+                if etag.starts_with("W/") {
+                    return None;
+                }
+                Some(format!("{} {etag}", request.uri()))
+            }
+        }
+    }
+
+    /// Returns the cached body for the key and codec, if any.
+    pub(crate) fn get(&self, key: &str, codec: Codec) -> Option<Bytes> {
+        self.lock().get(key, codec)
+    }
+
+    /// Starts recording a compressed body to cache under the key and codec.
+    pub(crate) fn recorder(&self, key: String, codec: Codec) -> Recorder {
+        Recorder {
+            capacity: self.capacity(),
+            cache: self.clone(),
+            key,
+            codec,
+            data: BytesMut::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Lru> {
+        // The entries stay consistent even if a thread panicked while holding the lock
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl fmt::Debug for CompressionCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let entries = self.lock();
+        f.debug_struct("CompressionCache")
+            .field("capacity", &entries.capacity)
+            .field("size", &entries.size)
+            .field("len", &entries.entries.len())
+            .field("key", &self.key.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+/// Collects the output of a compressed body, caching it once complete.
+pub(crate) struct Recorder {
+    cache: CompressionCache,
+    // Capacity of the cache, read once rather than locking it for every chunk
+    capacity: usize,
+    key: String,
+    codec: Codec,
+    data: BytesMut,
+}
+
+impl Recorder {
+    /// Appends compressed output.
+    ///
+    /// Returns false once the body is too large to be cached.
+    pub(crate) fn push(&mut self, data: &[u8]) -> bool {
+        if self.data.len() + data.len() > self.capacity {
+            return false;
+        }
+        self.data.extend_from_slice(data);
+        true
+    }
+
+    /// Caches the complete compressed body.
+    pub(crate) fn finish(self) {
+        self.cache
+            .lock()
+            .insert(self.key, self.codec, self.data.freeze());
+    }
+}
+
+/// Cached bodies with least recently used eviction.
+struct Lru {
+    capacity: usize,
+    size: usize,
+    entries: HashMap<(String, Codec), Entry>,
+    // Keys by the tick of their last use, oldest first
+    order: BTreeMap<u64, (String, Codec)>,
+    tick: u64,
+}
+
+struct Entry {
+    data: Bytes,
+    tick: u64,
+}
+
+impl Lru {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            size: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    fn get(&mut self, key: &str, codec: Codec) -> Option<Bytes> {
+        let tick = self.next_tick();
+        let key = (key.to_owned(), codec);
+        let entry = self.entries.get_mut(&key)?;
+        let previous = std::mem::replace(&mut entry.tick, tick);
+        let data = entry.data.clone();
+
+        self.order.remove(&previous);
+        self.order.insert(tick, key);
+        Some(data)
+    }
+
+    fn insert(&mut self, key: String, codec: Codec, data: Bytes) {
+        if data.len() > self.capacity {
+            return;
+        }
+        let key = (key, codec);
+        self.remove(&key);
+
+        let tick = self.next_tick();
+        self.size += data.len();
+        self.order.insert(tick, key.clone());
+        self.entries.insert(key, Entry { data, tick });
+
+        while self.size > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.size -= entry.data.len();
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &(String, Codec)) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.size -= entry.data.len();
+            self.order.remove(&entry.tick);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.size = 0;
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::Response;
+
+    fn any_codec() -> Codec {
+        crate::codec::CodecPreference::default()
+            .iter()
+            .next()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let codec = any_codec();
+        let cache = CompressionCache::new(10);
+        let mut a = cache.recorder("a".into(), codec);
+        assert!(a.push(b"aaaa"));
+        a.finish();
+        let mut b = cache.recorder("b".into(), codec);
+        assert!(b.push(b"bbbb"));
+        b.finish();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.size(), 8);
+
+        // Using "a" makes "b" the least recently used entry
+        assert_eq!(cache.get("a", codec).unwrap(), "aaaa");
+        let mut c = cache.recorder("c".into(), codec);
+        assert!(c.push(b"cccc"));
+        c.finish();
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.size(), 8);
+        assert!(cache.get("a", codec).is_some());
+        assert!(cache.get("b", codec).is_none());
+        assert!(cache.get("c", codec).is_some());
+
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.size(), 0);
+    }
+
+    #[test]
+    fn test_too_large_body_is_not_recorded() {
+        let codec = any_codec();
+        let cache = CompressionCache::new(4);
+        let mut recorder = cache.recorder("a".into(), codec);
+        assert!(recorder.push(b"abcd"));
+        assert!(!recorder.push(b"e"));
+    }
+
+    #[test]
+    fn test_default_key_uses_strong_etag() {
+        let cache = CompressionCache::new(1024);
+        let request = http::Request::get("/app.js").body(()).unwrap();
+        let request = RequestInfo::from_request(&request);
+        let key = |etag: Option<&'static str>| {
+            let mut response = Response::new(());
+            if let Some(etag) = etag {
+                response
+                    .headers_mut()
+                    .insert(header::ETAG, header::HeaderValue::from_static(etag));
+            }
+            let (parts, _) = response.into_parts();
+            cache.key(&request, &ResponseInfo::new(&parts, Default::default()))
+        };
+
+        assert_eq!(key(Some("\"v1\"")).unwrap(), "/app.js \"v1\"");
+        assert!(key(Some("W/\"v1\"")).is_none());
+        assert!(key(None).is_none());
+    }
+}
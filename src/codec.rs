@@ -1,17 +1,116 @@
-use compression_codecs::EncodeV2;
+use crate::accept_encoding::AcceptEncoding;
+use crate::content_encoder::{ContentEncoder, UnsupportedDecoder};
+use crate::dictionary::Dictionary;
 #[cfg(feature = "brotli")]
-use compression_codecs::brotli::{BrotliEncoder, params::EncoderParams as BrotliParams};
+use compression_codecs::brotli::{
+    BrotliDecoder, BrotliEncoder, params::EncoderParams as BrotliParams,
+};
 #[cfg(feature = "deflate")]
-use compression_codecs::deflate::DeflateEncoder;
+use compression_codecs::deflate::{DeflateDecoder, DeflateEncoder};
 #[cfg(feature = "gzip")]
-use compression_codecs::gzip::GzipEncoder;
+use compression_codecs::gzip::{GzipDecoder, GzipEncoder};
 #[cfg(feature = "zstd")]
-use compression_codecs::zstd::ZstdEncoder;
-#[cfg(any(feature = "gzip", feature = "deflate"))]
+use compression_codecs::zstd::{ZstdDecoder, ZstdEncoder};
+use compression_codecs::{DecodeV2, EncodeV2};
+#[cfg(any(feature = "brotli", feature = "gzip", feature = "deflate"))]
 use compression_core::Level;
+use http::{HeaderMap, HeaderValue};
+use std::io;
+use std::sync::Arc;
+
+/// Compression level used by a codec's encoder.
+///
+/// Levels trade CPU time for output size. [`CompressionLevel::Precise`] is
+/// interpreted by each codec and clamped to the range it supports.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CompressionLevel {
+    /// Fastest compression, usually producing the largest output.
+    Fastest,
+    /// The codec's default balance between speed and size.
+    #[default]
+    Default,
+    /// Best compression, usually the slowest.
+    Best,
+    /// A codec-specific quality value.
+    Precise(i32),
+}
+
+impl CompressionLevel {
+    /// Converts this level into the generic level understood by the encoders.
+    #[cfg(any(feature = "brotli", feature = "gzip", feature = "deflate"))]
+    fn into_core(self) -> Level {
+        match self {
+            CompressionLevel::Fastest => Level::Fastest,
+            CompressionLevel::Default => Level::Default,
+            CompressionLevel::Best => Level::Best,
+            CompressionLevel::Precise(quality) => Level::Precise(quality),
+        }
+    }
+
+    /// Returns the zstd level for this compression level.
+    #[cfg(feature = "zstd")]
+    fn zstd_level(self) -> i32 {
+        match self {
+            CompressionLevel::Fastest => 1,
+            CompressionLevel::Default => 3,
+            // 19 is the highest level that doesn't require zstd's "ultra" mode
+            CompressionLevel::Best => 19,
+            CompressionLevel::Precise(level) => level.clamp(1, 22),
+        }
+    }
+}
+
+/// Compression levels configured for each codec.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct CodecLevels {
+    #[cfg(feature = "zstd")]
+    pub(crate) zstd: CompressionLevel,
+    #[cfg(feature = "brotli")]
+    pub(crate) brotli: CompressionLevel,
+    #[cfg(feature = "gzip")]
+    pub(crate) gzip: CompressionLevel,
+    #[cfg(feature = "deflate")]
+    pub(crate) deflate: CompressionLevel,
+    pub(crate) custom: CompressionLevel,
+}
+
+impl CodecLevels {
+    /// Returns levels that use `level` for every codec.
+    pub(crate) fn all(level: CompressionLevel) -> Self {
+        Self {
+            #[cfg(feature = "zstd")]
+            zstd: level,
+            #[cfg(feature = "brotli")]
+            brotli: level,
+            #[cfg(feature = "gzip")]
+            gzip: level,
+            #[cfg(feature = "deflate")]
+            deflate: level,
+            custom: level,
+        }
+    }
+
+    /// Returns the level configured for the given codec.
+    pub(crate) fn get(&self, codec: Codec) -> CompressionLevel {
+        match codec {
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => self.zstd,
+            #[cfg(feature = "brotli")]
+            Codec::Brotli => self.brotli,
+            #[cfg(feature = "gzip")]
+            Codec::Gzip => self.gzip,
+            #[cfg(feature = "deflate")]
+            Codec::Deflate => self.deflate,
+            Codec::Custom(_) => self.custom,
+        }
+    }
+}
 
 /// Supported compression codecs.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+///
+/// Besides the built-in codecs, user-provided codings can be registered as
+/// [`ContentEncoder`]s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[non_exhaustive]
 pub enum Codec {
     /// Zstd compression.
@@ -26,6 +125,8 @@ pub enum Codec {
     /// Deflate compression.
     #[cfg(feature = "deflate")]
     Deflate,
+    /// A user-provided coding.
+    Custom(&'static dyn ContentEncoder),
 }
 
 impl Codec {
@@ -40,117 +141,356 @@ impl Codec {
             Codec::Gzip => "gzip",
             #[cfg(feature = "deflate")]
             Codec::Deflate => "deflate",
+            Codec::Custom(encoder) => encoder.name(),
         }
     }
 
-    /// Creates a new encoder for this codec.
-    pub fn encoder(&self) -> Box<dyn EncodeV2 + Send> {
+    /// Returns the file extension of precompressed variants, such as `br` for
+    /// `index.html.br`.
+    ///
+    /// Deflate and custom codecs have no conventional extension and return
+    /// `None`.
+    pub fn file_extension(&self) -> Option<&'static str> {
         match self {
             #[cfg(feature = "zstd")]
-            Codec::Zstd => Box::new(ZstdEncoder::new(3)),
+            Codec::Zstd => Some("zst"),
             #[cfg(feature = "brotli")]
-            Codec::Brotli => Box::new(BrotliEncoder::new(BrotliParams::default())),
+            Codec::Brotli => Some("br"),
             #[cfg(feature = "gzip")]
-            Codec::Gzip => Box::new(GzipEncoder::new(Level::Default.into())),
+            Codec::Gzip => Some("gz"),
             #[cfg(feature = "deflate")]
-            Codec::Deflate => Box::new(DeflateEncoder::new(Level::Default.into())),
+            Codec::Deflate => None,
+            Codec::Custom(_) => None,
         }
     }
 
-    /// Parses the Accept-Encoding header and returns the best supported codec.
-    ///
-    /// The header value is expected to be comma-separated encodings with optional
-    /// quality values (e.g., "gzip, br;q=1.0, zstd;q=0.8").
-    pub fn from_accept_encoding(header: &str) -> Option<Codec> {
-        let mut best_codec: Option<(Codec, f32)> = None;
+    /// Creates a new encoder for this codec using the given compression level.
+    pub fn encoder(&self, level: CompressionLevel) -> Box<dyn EncodeV2 + Send> {
+        match self {
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => Box::new(ZstdEncoder::new(level.zstd_level())),
+            #[cfg(feature = "brotli")]
+            Codec::Brotli => Box::new(BrotliEncoder::new(
+                BrotliParams::default().quality(level.into_core()),
+            )),
+            #[cfg(feature = "gzip")]
+            Codec::Gzip => Box::new(GzipEncoder::new(level.into_core().into())),
+            #[cfg(feature = "deflate")]
+            Codec::Deflate => Box::new(DeflateEncoder::new(level.into_core().into())),
+            Codec::Custom(encoder) => encoder.encoder(level),
+        }
+    }
 
-        for part in header.split(',') {
-            let part = part.trim();
-            let (encoding, quality) = parse_encoding_with_quality(part);
+    /// Returns the Content-Encoding of this codec's dictionary-compressed
+    /// variant from RFC 9842: `dcz` for zstd and `dcb` for brotli.
+    ///
+    /// Returns `None` for codecs without one.
+    pub fn dictionary_encoding(&self) -> Option<&'static str> {
+        match self {
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => Some("dcz"),
+            #[cfg(feature = "brotli")]
+            Codec::Brotli => Some("dcb"),
+            _ => None,
+        }
+    }
 
-            // Skip if quality is 0
-            if quality == 0.0 {
-                continue;
-            }
+    /// Creates a new encoder compressing with a shared dictionary, producing
+    /// a stream for this codec's [`dictionary_encoding`].
+    ///
+    /// Fails for codecs without a dictionary-compressed variant.
+    ///
+    /// [`dictionary_encoding`]: Codec::dictionary_encoding
+    #[cfg(not(any(feature = "zstd", feature = "brotli")))]
+    pub fn encoder_with_dictionary(
+        &self,
+        _level: CompressionLevel,
+        _dictionary: &Dictionary,
+    ) -> io::Result<Box<dyn EncodeV2 + Send>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "codec doesn't support dictionaries",
+        ))
+    }
 
-            #[allow(unused_mut)]
-            let mut codec = None;
+    /// Creates a new encoder compressing with a shared dictionary, producing
+    /// a stream for this codec's [`dictionary_encoding`].
+    ///
+    /// Fails for codecs without a dictionary-compressed variant.
+    ///
+    /// [`dictionary_encoding`]: Codec::dictionary_encoding
+    #[cfg(any(feature = "zstd", feature = "brotli"))]
+    pub fn encoder_with_dictionary(
+        &self,
+        level: CompressionLevel,
+        dictionary: &Dictionary,
+    ) -> io::Result<Box<dyn EncodeV2 + Send>> {
+        match self {
             #[cfg(feature = "zstd")]
-            if encoding == "zstd" {
-                codec = Some(Codec::Zstd);
+            Codec::Zstd => {
+                let encoder = ZstdEncoder::new_with_dict(level.zstd_level(), dictionary.bytes())?;
+                Ok(dictionary.framed(crate::dictionary::DCZ_MAGIC, Box::new(encoder)))
             }
             #[cfg(feature = "brotli")]
-            if codec.is_none() && (encoding == "br" || encoding == "brotli") {
-                codec = Some(Codec::Brotli);
-            }
-            #[cfg(feature = "gzip")]
-            if codec.is_none() && (encoding == "gzip" || encoding == "x-gzip") {
-                codec = Some(Codec::Gzip);
-            }
-            #[cfg(feature = "deflate")]
-            if codec.is_none() && encoding == "deflate" {
-                codec = Some(Codec::Deflate);
-            }
-
-            if let Some(codec) = codec {
-                match &best_codec {
-                    None => best_codec = Some((codec, quality)),
-                    Some((_, best_quality)) if quality > *best_quality => {
-                        best_codec = Some((codec, quality));
-                    }
-                    Some((best, best_quality)) if quality == *best_quality => {
-                        // Prefer zstd > brotli > gzip > deflate when quality is equal
-                        if codec.priority() < best.priority() {
-                            best_codec = Some((codec, quality));
-                        }
-                    }
-                    _ => {}
-                }
+            Codec::Brotli => {
+                let encoder = crate::dictionary::BrotliDictionaryEncoder::new(
+                    level.into_core(),
+                    dictionary.bytes(),
+                );
+                Ok(dictionary.framed(crate::dictionary::DCB_MAGIC, Box::new(encoder)))
             }
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "codec doesn't support dictionaries",
+            )),
         }
-
-        best_codec.map(|(codec, _)| codec)
     }
 
-    /// Returns the priority of this codec (lower is better).
-    fn priority(&self) -> u8 {
+    /// Creates a new decoder for this codec.
+    ///
+    /// Custom codecs without a decoder return one failing with
+    /// [`io::ErrorKind::Unsupported`].
+    pub fn decoder(&self) -> Box<dyn DecodeV2 + Send> {
         match self {
             #[cfg(feature = "zstd")]
-            Codec::Zstd => 0,
+            Codec::Zstd => Box::new(ZstdDecoder::new()),
             #[cfg(feature = "brotli")]
-            Codec::Brotli => 1,
+            Codec::Brotli => Box::new(BrotliDecoder::new()),
             #[cfg(feature = "gzip")]
-            Codec::Gzip => 2,
+            Codec::Gzip => Box::new(GzipDecoder::new()),
             #[cfg(feature = "deflate")]
-            Codec::Deflate => 3,
+            Codec::Deflate => Box::new(DeflateDecoder::new()),
+            Codec::Custom(encoder) => encoder
+                .decoder()
+                .unwrap_or_else(|| Box::new(UnsupportedDecoder(encoder.name()))),
         }
     }
+
+    /// Parses a single `Content-Encoding` coding, such as `gzip` or `br`.
+    ///
+    /// Returns `None` if the coding isn't supported.
+    pub fn from_content_encoding(coding: &str) -> Option<Codec> {
+        Self::from_token(coding.trim())
+    }
+
+    /// Parses the Accept-Encoding header and returns the best supported codec.
+    ///
+    /// The header value is expected to be comma-separated encodings with optional
+    /// quality values (e.g., "gzip, br;q=1.0, zstd;q=0.8"). When several codecs
+    /// share the highest quality, zstd is preferred over brotli, gzip and deflate.
+    ///
+    /// Returns `None` if the response should not be compressed; see
+    /// [`AcceptEncoding`] to tell `identity` apart from a refusal of every coding.
+    pub fn from_accept_encoding(header: &str) -> Option<Codec> {
+        CodecPreference::default().negotiate(header)
+    }
+
+    /// Checks if an `Accept-Encoding` coding refers to this codec, including
+    /// aliases like `x-gzip`.
+    pub(crate) fn matches_token(&self, coding: &str) -> bool {
+        match self {
+            Codec::Custom(encoder) => Codec::from_token(coding)
+                .map_or(coding, |codec| codec.content_encoding())
+                .eq_ignore_ascii_case(encoder.name()),
+            codec => Codec::from_token(coding) == Some(*codec),
+        }
+    }
+
+    /// Returns the priority used to place a registered codec among the
+    /// others; built-in codecs have priority 0.
+    fn priority(&self) -> i32 {
+        match self {
+            Codec::Custom(encoder) => encoder.priority(),
+            _ => 0,
+        }
+    }
+
+    /// Parses a single encoding token into a built-in codec, if it is supported.
+    pub(crate) fn from_token(encoding: &str) -> Option<Codec> {
+        #[cfg(feature = "zstd")]
+        if encoding.eq_ignore_ascii_case("zstd") {
+            return Some(Codec::Zstd);
+        }
+        #[cfg(feature = "brotli")]
+        if encoding.eq_ignore_ascii_case("br") || encoding.eq_ignore_ascii_case("brotli") {
+            return Some(Codec::Brotli);
+        }
+        #[cfg(feature = "gzip")]
+        if encoding.eq_ignore_ascii_case("gzip") || encoding.eq_ignore_ascii_case("x-gzip") {
+            return Some(Codec::Gzip);
+        }
+        #[cfg(feature = "deflate")]
+        if encoding.eq_ignore_ascii_case("deflate") {
+            return Some(Codec::Deflate);
+        }
+        None
+    }
+}
+
+/// Returns the codec the compression middleware would pick for a request.
+///
+/// This uses the same Accept-Encoding negotiation as [`CompressionLayer`] with
+/// its default codec preference, so handlers and other middleware can make
+/// consistent decisions.
+///
+/// [`CompressionLayer`]: crate::CompressionLayer
+pub fn negotiate(headers: &HeaderMap) -> Option<Codec> {
+    CodecPreference::default().negotiate_headers(headers)
+}
+
+/// Enabled codecs in the default order of preference.
+const DEFAULT_ORDER: &[Codec] = &[
+    #[cfg(feature = "zstd")]
+    Codec::Zstd,
+    #[cfg(feature = "brotli")]
+    Codec::Brotli,
+    #[cfg(feature = "gzip")]
+    Codec::Gzip,
+    #[cfg(feature = "deflate")]
+    Codec::Deflate,
+];
+
+/// The set of codecs the server is willing to use, in order of preference.
+///
+/// The order is used to break ties between codecs the client accepts with
+/// equal quality.
+#[derive(Debug, Clone)]
+pub(crate) struct CodecPreference {
+    // Shared, as services clone it for every request
+    order: Arc<[Codec]>,
 }
 
-/// Parses an encoding entry like "gzip" or "br;q=0.8" into (encoding, quality).
-fn parse_encoding_with_quality(s: &str) -> (&str, f32) {
-    let mut parts = s.splitn(2, ';');
-    let encoding = parts.next().unwrap_or("").trim();
-
-    let quality = parts
-        .next()
-        .and_then(|q| {
-            let q = q.trim();
-            if q.starts_with("q=") || q.starts_with("Q=") {
-                q[2..].parse::<f32>().ok()
-            } else {
-                None
+impl Default for CodecPreference {
+    fn default() -> Self {
+        Self {
+            order: DEFAULT_ORDER.into(),
+        }
+    }
+}
+
+impl CodecPreference {
+    /// Moves the given codecs to the front, in the given order.
+    ///
+    /// Codecs that aren't listed keep their relative order after the listed
+    /// ones. Disabled codecs stay disabled.
+    pub(crate) fn prefer<I>(&mut self, codecs: I)
+    where
+        I: IntoIterator<Item = Codec>,
+    {
+        let mut order = Vec::with_capacity(self.order.len());
+        for codec in codecs.into_iter().chain(self.order.iter().copied()) {
+            if self.rank(codec).is_some() && !order.contains(&codec) {
+                order.push(codec);
             }
-        })
-        .unwrap_or(1.0);
+        }
+        self.order = order.into();
+    }
+
+    /// Removes the given codec from the set of codecs used for compression.
+    pub(crate) fn disable(&mut self, codec: Codec) {
+        self.order = self
+            .order
+            .iter()
+            .filter(|c| **c != codec)
+            .copied()
+            .collect();
+    }
 
-    (encoding, quality)
+    /// Adds a custom codec.
+    ///
+    /// It replaces an enabled codec with the same content coding in place,
+    /// and is otherwise placed before the first codec with a lower priority.
+    pub(crate) fn register(&mut self, encoder: &'static dyn ContentEncoder) {
+        let codec = Codec::Custom(encoder);
+        let mut order = self.order.to_vec();
+        if let Some(slot) = order
+            .iter_mut()
+            .find(|c| c.content_encoding().eq_ignore_ascii_case(encoder.name()))
+        {
+            *slot = codec;
+        } else {
+            let index = order
+                .iter()
+                .position(|c| c.priority() < codec.priority())
+                .unwrap_or(order.len());
+            order.insert(index, codec);
+        }
+        self.order = order.into();
+    }
+
+    /// Returns the position of the codec in the preference order, or `None` if
+    /// it is disabled. Lower is better.
+    fn rank(&self, codec: Codec) -> Option<usize> {
+        self.order.iter().position(|c| *c == codec)
+    }
+
+    /// Returns the enabled codecs in order of preference.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = Codec> + '_ {
+        self.order.iter().copied()
+    }
+
+    /// Returns whether the codec is enabled.
+    pub(crate) fn is_enabled(&self, codec: Codec) -> bool {
+        self.rank(codec).is_some()
+    }
+
+    /// Returns an `Accept-Encoding` value listing the enabled codecs, or `None`
+    /// if every codec is disabled.
+    pub(crate) fn accept_encoding(&self) -> Option<HeaderValue> {
+        let codings: Vec<_> = self.iter().map(|codec| codec.content_encoding()).collect();
+        if codings.is_empty() {
+            return None;
+        }
+        HeaderValue::from_str(&codings.join(", ")).ok()
+    }
+
+    /// Returns the best enabled codec for the request's Accept-Encoding headers.
+    ///
+    /// All Accept-Encoding header lines are considered, as if they were a
+    /// single comma-separated list.
+    pub(crate) fn negotiate_headers(&self, headers: &HeaderMap) -> Option<Codec> {
+        AcceptEncoding::from_headers(headers)
+            .negotiate_with(self)
+            .codec()
+    }
+
+    /// Parses the Accept-Encoding header and returns the best enabled codec.
+    pub(crate) fn negotiate(&self, header: &str) -> Option<Codec> {
+        AcceptEncoding::parse(header).negotiate_with(self).codec()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_from_content_encoding() {
+        #[cfg(feature = "gzip")]
+        assert_eq!(Codec::from_content_encoding(" GZIP "), Some(Codec::Gzip));
+        #[cfg(feature = "brotli")]
+        assert_eq!(Codec::from_content_encoding("br"), Some(Codec::Brotli));
+        assert_eq!(Codec::from_content_encoding("compress"), None);
+        assert_eq!(Codec::from_content_encoding("identity"), None);
+    }
+
+    #[test]
+    #[cfg(all(feature = "zstd", feature = "gzip", feature = "deflate"))]
+    fn test_accept_encoding_value() {
+        let mut preference = CodecPreference::default();
+        preference.disable(Codec::Deflate);
+        let value = preference.accept_encoding().unwrap();
+        assert!(value.to_str().unwrap().starts_with("zstd, "));
+        assert!(value.to_str().unwrap().ends_with("gzip"));
+        assert!(!preference.is_enabled(Codec::Deflate));
+
+        for codec in [Codec::Zstd, Codec::Gzip] {
+            preference.disable(codec);
+        }
+        #[cfg(not(feature = "brotli"))]
+        assert!(preference.accept_encoding().is_none());
+    }
+
     #[test]
     fn test_content_encoding() {
         #[cfg(feature = "zstd")]
@@ -163,6 +503,26 @@ mod tests {
         assert_eq!(Codec::Deflate.content_encoding(), "deflate");
     }
 
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn test_zstd_levels() {
+        assert_eq!(CompressionLevel::Fastest.zstd_level(), 1);
+        assert_eq!(CompressionLevel::Default.zstd_level(), 3);
+        assert_eq!(CompressionLevel::Best.zstd_level(), 19);
+        assert_eq!(CompressionLevel::Precise(100).zstd_level(), 22);
+        assert_eq!(CompressionLevel::Precise(-5).zstd_level(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_codec_levels() {
+        let mut levels = CodecLevels::all(CompressionLevel::Best);
+        levels.gzip = CompressionLevel::Fastest;
+        assert_eq!(levels.get(Codec::Gzip), CompressionLevel::Fastest);
+        #[cfg(feature = "zstd")]
+        assert_eq!(levels.get(Codec::Zstd), CompressionLevel::Best);
+    }
+
     #[test]
     fn test_from_accept_encoding_simple() {
         #[cfg(feature = "zstd")]
@@ -204,6 +564,59 @@ mod tests {
         assert_eq!(Codec::from_accept_encoding("compress"), None);
     }
 
+    #[test]
+    #[cfg(all(feature = "zstd", feature = "brotli", feature = "gzip"))]
+    fn test_preference_order() {
+        let mut preference = CodecPreference::default();
+        preference.prefer([Codec::Gzip, Codec::Brotli]);
+        assert_eq!(preference.negotiate("zstd, br, gzip"), Some(Codec::Gzip));
+        assert_eq!(preference.negotiate("zstd, br"), Some(Codec::Brotli));
+        assert_eq!(preference.negotiate("zstd"), Some(Codec::Zstd));
+
+        // Quality still wins over the server's order
+        assert_eq!(preference.negotiate("gzip;q=0.5, zstd"), Some(Codec::Zstd));
+    }
+
+    #[test]
+    #[cfg(all(feature = "zstd", feature = "gzip"))]
+    fn test_preference_disable() {
+        let mut preference = CodecPreference::default();
+        preference.disable(Codec::Zstd);
+        assert_eq!(preference.negotiate("zstd, gzip"), Some(Codec::Gzip));
+        assert_eq!(preference.negotiate("zstd"), None);
+
+        // Preferring a disabled codec doesn't re-enable it
+        preference.prefer([Codec::Zstd]);
+        assert_eq!(preference.negotiate("zstd"), None);
+    }
+
+    #[test]
+    #[cfg(all(feature = "zstd", feature = "gzip"))]
+    fn test_from_accept_encoding_wildcard() {
+        assert_eq!(Codec::from_accept_encoding("*"), Some(Codec::Zstd));
+        assert_eq!(
+            Codec::from_accept_encoding("*;q=0, gzip"),
+            Some(Codec::Gzip)
+        );
+        assert_eq!(Codec::from_accept_encoding("*;q=0"), None);
+    }
+
+    #[test]
+    fn test_negotiate_without_header() {
+        assert_eq!(negotiate(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    #[cfg(all(feature = "gzip", feature = "brotli"))]
+    fn test_negotiate_multiple_header_lines() {
+        use http::header;
+
+        let mut headers = HeaderMap::new();
+        headers.append(header::ACCEPT_ENCODING, "gzip;q=0.5".parse().unwrap());
+        headers.append(header::ACCEPT_ENCODING, "br".parse().unwrap());
+        assert_eq!(negotiate(&headers), Some(Codec::Brotli));
+    }
+
     #[test]
     #[cfg(all(feature = "gzip", feature = "brotli"))]
     fn test_from_accept_encoding_quality_zero() {
@@ -0,0 +1,270 @@
+use crate::codec::CompressionLevel;
+use compression_codecs::{DecodeV2, EncodeV2};
+use compression_core::util::{PartialBuffer, WriteBuffer};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io;
+
+/// A content coding provided by the user, such as `lz4`, `xz` or a
+/// hardware-accelerated `gzip`.
+///
+/// Encoders are registered with [`CompressionLayer::register`] and take part
+/// in `Accept-Encoding` negotiation like the built-in codecs, as
+/// [`Codec::Custom`]. They are registered as `&'static` references, so a
+/// `static` or a leaked `Box` is needed:
+///
+/// ```ignore
+/// use http_response_compression::{CompressionLayer, CompressionLevel, ContentEncoder, EncodeV2};
+///
+/// struct Lz4;
+///
+/// impl ContentEncoder for Lz4 {
+///     fn name(&self) -> &'static str {
+///         "lz4"
+///     }
+///
+///     fn encoder(&self, level: CompressionLevel) -> Box<dyn EncodeV2 + Send> {
+///         Box::new(Lz4Encoder::new(level))
+///     }
+/// }
+///
+/// static LZ4: Lz4 = Lz4;
+///
+/// let layer = CompressionLayer::new().register(&LZ4);
+/// ```
+///
+/// Two encoders are equal if their names are, ignoring ASCII case.
+///
+/// [`CompressionLayer::register`]: crate::CompressionLayer::register
+/// [`Codec::Custom`]: crate::Codec::Custom
+pub trait ContentEncoder: Send + Sync {
+    /// Returns the content coding token used in `Accept-Encoding` and
+    /// `Content-Encoding`, such as `lz4`.
+    fn name(&self) -> &'static str;
+
+    /// Returns the priority of this coding relative to the built-in codecs,
+    /// used to break ties between codings the client accepts with equal
+    /// quality.
+    ///
+    /// Codings with a positive priority are preferred over the built-in
+    /// codecs, the others come after them. Higher priorities come first.
+    /// Defaults to `0`.
+    fn priority(&self) -> i32 {
+        0
+    }
+
+    /// Creates a new encoder using the given compression level.
+    fn encoder(&self, level: CompressionLevel) -> Box<dyn EncodeV2 + Send>;
+
+    /// Creates a new decoder, if this coding can be decoded.
+    ///
+    /// Defaults to `None`, in which case [`Codec::decoder`] returns a decoder
+    /// failing with [`io::ErrorKind::Unsupported`].
+    ///
+    /// [`Codec::decoder`]: crate::Codec::decoder
+    fn decoder(&self) -> Option<Box<dyn DecodeV2 + Send>> {
+        None
+    }
+}
+
+impl fmt::Debug for dyn ContentEncoder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.name(), f)
+    }
+}
+
+impl PartialEq for dyn ContentEncoder {
+    fn eq(&self, other: &Self) -> bool {
+        self.name().eq_ignore_ascii_case(other.name())
+    }
+}
+
+impl Eq for dyn ContentEncoder {}
+
+impl Hash for dyn ContentEncoder {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for b in self.name().bytes() {
+            state.write_u8(b.to_ascii_lowercase());
+        }
+    }
+}
+
+/// Decoder for custom codings that can't be decoded.
+pub(crate) struct UnsupportedDecoder(pub(crate) &'static str);
+
+impl UnsupportedDecoder {
+    fn error(&self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("no decoder for content coding {}", self.0),
+        )
+    }
+}
+
+impl DecodeV2 for UnsupportedDecoder {
+    fn reinit(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn decode(
+        &mut self,
+        _input: &mut PartialBuffer<&[u8]>,
+        _output: &mut WriteBuffer<'_>,
+    ) -> io::Result<bool> {
+        Err(self.error())
+    }
+
+    fn flush(&mut self, _output: &mut WriteBuffer<'_>) -> io::Result<bool> {
+        Err(self.error())
+    }
+
+    fn finish(&mut self, _output: &mut WriteBuffer<'_>) -> io::Result<bool> {
+        Err(self.error())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::codec::{Codec, CodecPreference};
+
+    /// A coding that copies its input unchanged.
+    pub(crate) struct CopyCoding {
+        pub(crate) name: &'static str,
+        pub(crate) priority: i32,
+    }
+
+    impl ContentEncoder for CopyCoding {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn priority(&self) -> i32 {
+            self.priority
+        }
+
+        fn encoder(&self, _level: CompressionLevel) -> Box<dyn EncodeV2 + Send> {
+            Box::new(CopyEncoder)
+        }
+    }
+
+    struct CopyEncoder;
+
+    impl EncodeV2 for CopyEncoder {
+        fn encode(
+            &mut self,
+            input: &mut PartialBuffer<&[u8]>,
+            output: &mut WriteBuffer<'_>,
+        ) -> io::Result<()> {
+            output.copy_unwritten_from(input);
+            Ok(())
+        }
+
+        fn flush(&mut self, _output: &mut WriteBuffer<'_>) -> io::Result<bool> {
+            Ok(true)
+        }
+
+        fn finish(&mut self, _output: &mut WriteBuffer<'_>) -> io::Result<bool> {
+            Ok(true)
+        }
+    }
+
+    pub(crate) static COPY: CopyCoding = CopyCoding {
+        name: "copy",
+        priority: 0,
+    };
+    static PREFERRED: CopyCoding = CopyCoding {
+        name: "preferred",
+        priority: 1,
+    };
+
+    #[test]
+    fn test_custom_codec_equality() {
+        use std::hash::BuildHasher;
+
+        static UPPER: CopyCoding = CopyCoding {
+            name: "COPY",
+            priority: 0,
+        };
+        assert_eq!(Codec::Custom(&COPY), Codec::Custom(&UPPER));
+        assert_ne!(Codec::Custom(&COPY), Codec::Custom(&PREFERRED));
+        let state = std::collections::hash_map::RandomState::new();
+        assert_eq!(
+            state.hash_one(Codec::Custom(&COPY)),
+            state.hash_one(Codec::Custom(&UPPER))
+        );
+        assert_eq!(format!("{:?}", Codec::Custom(&COPY)), "Custom(\"copy\")");
+    }
+
+    #[test]
+    fn test_register_by_priority() {
+        let mut preference = CodecPreference::default();
+        let builtin: Vec<_> = preference.iter().collect();
+        preference.register(&COPY);
+        preference.register(&PREFERRED);
+
+        let order: Vec<_> = preference.iter().collect();
+        assert_eq!(order[0], Codec::Custom(&PREFERRED));
+        assert_eq!(order[1..=builtin.len()], builtin[..]);
+        assert_eq!(order.last(), Some(&Codec::Custom(&COPY)));
+
+        assert_eq!(
+            preference.negotiate("gzip, deflate, zstd, br, copy, preferred"),
+            Some(Codec::Custom(&PREFERRED))
+        );
+        assert_eq!(
+            preference.negotiate("gzip;q=0.5, COPY"),
+            Some(Codec::Custom(&COPY))
+        );
+        assert_eq!(
+            preference.accept_encoding().unwrap(),
+            order
+                .iter()
+                .map(|codec| codec.content_encoding())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_register_replaces_builtin() {
+        static GZIP: CopyCoding = CopyCoding {
+            name: "gzip",
+            priority: 0,
+        };
+        let mut preference = CodecPreference::default();
+        let rank = preference.iter().position(|c| c == Codec::Gzip);
+        preference.register(&GZIP);
+
+        assert!(!preference.is_enabled(Codec::Gzip));
+        assert_eq!(
+            preference.iter().position(|c| c == Codec::Custom(&GZIP)),
+            rank
+        );
+        assert_eq!(preference.negotiate("x-gzip"), Some(Codec::Custom(&GZIP)));
+    }
+
+    #[test]
+    fn test_unsupported_decoder() {
+        let mut decoder = Codec::Custom(&COPY).decoder();
+        let mut output = [0u8; 16];
+        let error = decoder
+            .decode(
+                &mut PartialBuffer::new(&b"data"[..]),
+                &mut WriteBuffer::new_initialized(&mut output),
+            )
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid content coding")]
+    fn test_register_invalid_name() {
+        static INVALID: CopyCoding = CopyCoding {
+            name: "not a token",
+            priority: 0,
+        };
+        let _ = crate::CompressionLayer::new().register(&INVALID);
+    }
+}
@@ -0,0 +1,273 @@
+use crate::accept_encoding::is_token;
+use crate::predicate::{Predicate, RequestInfo, ResponseInfo};
+use http::header;
+
+/// Content types that are already compressed, and aren't worth compressing again.
+const DEFAULT_DENIED: &[&str] = &[
+    "image/*",
+    "video/*",
+    "audio/*",
+    "font/woff",
+    "font/woff2",
+    "application/octet-stream",
+    "application/zip",
+    "application/gzip",
+    "application/x-gzip",
+    "application/zstd",
+    "application/x-brotli",
+    "application/x-bzip2",
+    "application/x-xz",
+    "application/x-7z-compressed",
+    "application/vnd.rar",
+    "application/x-rar-compressed",
+];
+
+/// Text-based content types within otherwise denied families.
+const DEFAULT_ALLOWED: &[&str] = &["image/svg+xml"];
+
+/// Decides which content types are compressed, based on allowed and denied
+/// MIME patterns.
+///
+/// Patterns are `type/subtype`, `type/*` or `*/*`, matched case-insensitively
+/// against the `Content-Type` header with any parameters (such as `charset`)
+/// ignored. When several patterns match, the most specific one decides; for
+/// example denying `image/*` and allowing `image/svg+xml` compresses SVG but
+/// no other image. Adding a pattern that is already present replaces it.
+///
+/// Responses without a `Content-Type`, or with one that can't be parsed, are
+/// compressed.
+///
+/// The default filter denies images (except SVG), video, audio, WOFF fonts,
+/// `application/octet-stream` and common archive formats.
+///
+/// ```ignore
+/// use http_response_compression::ContentTypeFilter;
+///
+/// // Only compress text and JSON
+/// let filter = ContentTypeFilter::new()
+///     .deny("*/*")
+///     .allow("text/*")
+///     .allow("application/json");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentTypeFilter {
+    rules: Vec<Rule>,
+}
+
+/// A MIME pattern and whether matching content types are compressed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    pattern: MimePattern,
+    allow: bool,
+}
+
+/// A parsed MIME pattern; `None` stands for the `*` wildcard.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct MimePattern {
+    ty: Option<String>,
+    subtype: Option<String>,
+}
+
+impl ContentTypeFilter {
+    /// Creates a filter that compresses every content type.
+    pub fn new() -> Self {
+        Self { rules: Vec::new() }
+    }
+
+    /// Compresses content types matching the pattern.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pattern isn't `type/subtype`, `type/*` or `*/*`.
+    pub fn allow(self, pattern: &str) -> Self {
+        self.rule(pattern, true)
+    }
+
+    /// Doesn't compress content types matching the pattern.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pattern isn't `type/subtype`, `type/*` or `*/*`.
+    pub fn deny(self, pattern: &str) -> Self {
+        self.rule(pattern, false)
+    }
+
+    fn rule(mut self, pattern: &str, allow: bool) -> Self {
+        let pattern = MimePattern::parse(pattern)
+            .unwrap_or_else(|| panic!("invalid MIME pattern: {pattern:?}"));
+        self.rules.retain(|rule| rule.pattern != pattern);
+        self.rules.push(Rule { pattern, allow });
+        self
+    }
+
+    /// Returns whether a response with the given `Content-Type` value should be
+    /// compressed.
+    pub fn is_compressible(&self, content_type: &str) -> bool {
+        let Some((ty, subtype)) = parse_essence(content_type) else {
+            return true;
+        };
+
+        self.rules
+            .iter()
+            .filter(|rule| rule.pattern.matches(ty, subtype))
+            .max_by_key(|rule| rule.pattern.specificity())
+            .is_none_or(|rule| rule.allow)
+    }
+}
+
+impl Default for ContentTypeFilter {
+    fn default() -> Self {
+        let filter = DEFAULT_DENIED
+            .iter()
+            .fold(Self::new(), |filter, pattern| filter.deny(pattern));
+        DEFAULT_ALLOWED
+            .iter()
+            .fold(filter, |filter, pattern| filter.allow(pattern))
+    }
+}
+
+impl Predicate for ContentTypeFilter {
+    fn should_compress(&self, _: &RequestInfo, response: &ResponseInfo<'_>) -> bool {
+        response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_none_or(|content_type| self.is_compressible(content_type))
+    }
+}
+
+impl MimePattern {
+    /// Parses a pattern like `text/html`, `image/*` or `*/*`.
+    fn parse(pattern: &str) -> Option<Self> {
+        let (ty, subtype) = split_essence(pattern.trim())?;
+        let wildcard = |part: &str| (part != "*").then(|| part.to_ascii_lowercase());
+        let pattern = Self {
+            ty: wildcard(ty),
+            subtype: wildcard(subtype),
+        };
+        // `*/subtype` isn't a meaningful pattern
+        if pattern.ty.is_none() && pattern.subtype.is_some() {
+            return None;
+        }
+        Some(pattern)
+    }
+
+    /// Returns whether the pattern matches the type and subtype.
+    fn matches(&self, ty: &str, subtype: &str) -> bool {
+        self.ty
+            .as_deref()
+            .is_none_or(|p| p.eq_ignore_ascii_case(ty))
+            && self
+                .subtype
+                .as_deref()
+                .is_none_or(|p| p.eq_ignore_ascii_case(subtype))
+    }
+
+    /// Returns how specific the pattern is; more specific patterns take precedence.
+    fn specificity(&self) -> u8 {
+        u8::from(self.ty.is_some()) + u8::from(self.subtype.is_some())
+    }
+}
+
+/// Parses the `type/subtype` part of a `Content-Type` value, ignoring parameters.
+pub(crate) fn parse_essence(content_type: &str) -> Option<(&str, &str)> {
+    let essence = content_type.split(';').next()?.trim();
+    split_essence(essence)
+}
+
+/// Splits `type/subtype`, checking that both are valid tokens.
+fn split_essence(essence: &str) -> Option<(&str, &str)> {
+    let (ty, subtype) = essence.split_once('/')?;
+    (is_token(ty) && is_token(subtype)).then_some((ty, subtype))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_essence() {
+        assert_eq!(parse_essence("text/html"), Some(("text", "html")));
+        assert_eq!(
+            parse_essence(" Text/HTML ; charset=utf-8"),
+            Some(("Text", "HTML"))
+        );
+        assert_eq!(parse_essence("text"), None);
+        assert_eq!(parse_essence("text/"), None);
+        assert_eq!(parse_essence("/html"), None);
+        assert_eq!(parse_essence("text/ht ml"), None);
+        assert_eq!(parse_essence(""), None);
+    }
+
+    #[test]
+    fn test_default_filter() {
+        let filter = ContentTypeFilter::default();
+        for content_type in [
+            "text/html",
+            "text/plain; charset=utf-8",
+            "application/json",
+            "application/javascript",
+            "image/svg+xml",
+            "IMAGE/SVG+XML; charset=utf-8",
+            "application/grpc",
+            "not a mime type",
+        ] {
+            assert!(filter.is_compressible(content_type), "{content_type}");
+        }
+        for content_type in [
+            "image/png",
+            "Image/JPEG",
+            "video/mp4",
+            "audio/ogg; codecs=opus",
+            "font/woff2",
+            "application/zip",
+            "application/gzip",
+            "application/octet-stream",
+        ] {
+            assert!(!filter.is_compressible(content_type), "{content_type}");
+        }
+    }
+
+    #[test]
+    fn test_empty_filter_compresses_everything() {
+        let filter = ContentTypeFilter::new();
+        assert!(filter.is_compressible("image/png"));
+        assert!(filter.is_compressible("application/zip"));
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let filter = ContentTypeFilter::new()
+            .deny("*/*")
+            .allow("text/*")
+            .allow("application/json");
+        assert!(filter.is_compressible("text/css"));
+        assert!(filter.is_compressible("application/json; charset=utf-8"));
+        assert!(!filter.is_compressible("application/xml"));
+        assert!(!filter.is_compressible("image/svg+xml"));
+    }
+
+    #[test]
+    fn test_most_specific_wins() {
+        let filter = ContentTypeFilter::new()
+            .allow("text/*")
+            .deny("text/event-stream");
+        assert!(filter.is_compressible("text/html"));
+        assert!(!filter.is_compressible("text/event-stream"));
+    }
+
+    #[test]
+    fn test_rule_replaces_existing_pattern() {
+        let filter = ContentTypeFilter::default().allow("application/octet-stream");
+        assert!(filter.is_compressible("application/octet-stream"));
+
+        let filter = filter.deny("Application/Octet-Stream");
+        assert!(!filter.is_compressible("application/octet-stream"));
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid MIME pattern")]
+    fn test_invalid_pattern() {
+        let _ = ContentTypeFilter::new().deny("*/json");
+    }
+}
@@ -0,0 +1,313 @@
+use crate::codec::{Codec, CodecPreference};
+use crate::decompression_body::{DecompressionBody, DecompressionLimits, content_coding};
+use http::{Method, Request, Response, StatusCode, header};
+use pin_project_lite::pin_project;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tower::{Layer, Service};
+
+/// A Tower layer that decompresses HTTP response bodies, for use in clients.
+///
+/// Outgoing requests without an `Accept-Encoding` header get one listing the
+/// enabled codecs. Responses with a supported `Content-Encoding` have their
+/// body decoded and the `Content-Encoding` and `Content-Length` headers
+/// removed; other responses are passed through unchanged.
+#[derive(Debug, Clone, Default)]
+pub struct DecompressionLayer {
+    codecs: CodecPreference,
+    limits: DecompressionLimits,
+}
+
+impl DecompressionLayer {
+    /// Creates a new decompression layer accepting every enabled codec.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Disables a codec, so it isn't advertised and responses encoded with it
+    /// are passed through unchanged.
+    pub fn disable(mut self, codec: Codec) -> Self {
+        self.codecs.disable(codec);
+        self
+    }
+
+    /// Sets the maximum size of a decompressed body, in bytes.
+    ///
+    /// Reading further fails with [`DecompressionError::SizeLimitExceeded`].
+    /// There is no limit by default.
+    ///
+    /// [`DecompressionError::SizeLimitExceeded`]: crate::DecompressionError::SizeLimitExceeded
+    pub fn max_decompressed_size(mut self, bytes: u64) -> Self {
+        self.limits.max_size = Some(bytes);
+        self
+    }
+
+    /// Sets the maximum ratio of decompressed to compressed size.
+    ///
+    /// Reading further fails with [`DecompressionError::RatioLimitExceeded`].
+    /// The ratio is only enforced once more than 64 KiB has been decompressed,
+    /// so small but highly compressible bodies are accepted. There is no limit
+    /// by default.
+    ///
+    /// [`DecompressionError::RatioLimitExceeded`]: crate::DecompressionError::RatioLimitExceeded
+    pub fn max_expansion_ratio(mut self, ratio: u64) -> Self {
+        self.limits.max_ratio = Some(ratio);
+        self
+    }
+}
+
+impl<S> Layer<S> for DecompressionLayer {
+    type Service = DecompressionService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        DecompressionService {
+            inner,
+            codecs: self.codecs.clone(),
+            limits: self.limits,
+        }
+    }
+}
+
+/// A Tower service that decompresses HTTP response bodies.
+///
+/// See [`DecompressionLayer`] for details.
+#[derive(Debug, Clone)]
+pub struct DecompressionService<S> {
+    inner: S,
+    codecs: CodecPreference,
+    limits: DecompressionLimits,
+}
+
+impl<S> DecompressionService<S> {
+    /// Creates a new decompression service accepting every enabled codec.
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            codecs: CodecPreference::default(),
+            limits: DecompressionLimits::default(),
+        }
+    }
+
+    /// Returns a reference to the inner service.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner service.
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Consumes this service, returning the inner service.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for DecompressionService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+{
+    type Response = Response<DecompressionBody<ResBody>>;
+    type Error = S::Error;
+    type Future = DecompressionFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        if !req.headers().contains_key(header::ACCEPT_ENCODING)
+            && let Some(accept) = self.codecs.accept_encoding()
+        {
+            req.headers_mut().insert(header::ACCEPT_ENCODING, accept);
+        }
+        let is_head = req.method() == Method::HEAD;
+
+        DecompressionFuture {
+            inner: self.inner.call(req),
+            codecs: self.codecs.clone(),
+            limits: self.limits,
+            is_head,
+        }
+    }
+}
+
+pin_project! {
+    /// Future for decompression service responses.
+    pub struct DecompressionFuture<F> {
+        #[pin]
+        inner: F,
+        codecs: CodecPreference,
+        limits: DecompressionLimits,
+        is_head: bool,
+    }
+}
+
+impl<F, B, E> Future for DecompressionFuture<F>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+{
+    type Output = Result<Response<DecompressionBody<B>>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let (mut parts, body) = ready!(this.inner.poll(cx))?.into_parts();
+
+        // Bodiless responses keep the headers describing the body a GET would get
+        let has_body = !*this.is_head
+            && !parts.status.is_informational()
+            && parts.status != StatusCode::NO_CONTENT
+            && parts.status != StatusCode::NOT_MODIFIED;
+
+        let body = match content_coding(&parts.headers, this.codecs) {
+            Ok(Some(codec)) if has_body => {
+                parts.headers.remove(header::CONTENT_ENCODING);
+                parts.headers.remove(header::CONTENT_LENGTH);
+                DecompressionBody::with_limits(body, codec, *this.limits)
+            }
+            _ => DecompressionBody::passthrough(body),
+        };
+
+        Poll::Ready(Ok(Response::from_parts(parts, body)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use http::HeaderValue;
+    use http_body_util::{BodyExt, Full};
+    use std::convert::Infallible;
+    use tower::ServiceExt;
+
+    fn block_on<T>(future: impl Future<Output = T>) -> T {
+        let mut future = std::pin::pin!(future);
+        let mut cx = Context::from_waker(std::task::Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    /// A server that compresses its body with the first codec the request
+    /// accepts, echoing the request's Accept-Encoding in a header.
+    fn server(req: Request<()>) -> impl Future<Output = Result<Response<Full<Bytes>>, Infallible>> {
+        let accept = req.headers().get(header::ACCEPT_ENCODING).cloned();
+        let codec = crate::negotiate(req.headers());
+        async move {
+            let body = Bytes::from("hello from the server");
+            let mut response = match codec {
+                Some(codec) => {
+                    let compressed =
+                        crate::CompressionBody::compressed(Full::new(body), codec, false);
+                    let compressed = compressed.collect().await.unwrap().to_bytes();
+                    let mut response = Response::new(Full::new(compressed));
+                    response.headers_mut().insert(
+                        header::CONTENT_ENCODING,
+                        HeaderValue::from_static(codec.content_encoding()),
+                    );
+                    response
+                }
+                None => Response::new(Full::new(body)),
+            };
+            if let Some(accept) = accept {
+                response.headers_mut().insert("x-accept-encoding", accept);
+            }
+            Ok(response)
+        }
+    }
+
+    #[test]
+    fn test_adds_accept_encoding_and_decodes() {
+        let service = DecompressionLayer::new().layer(tower::service_fn(server));
+        let response = block_on(service.oneshot(Request::new(()))).unwrap();
+
+        assert_eq!(
+            response.headers()["x-accept-encoding"],
+            CodecPreference::default().accept_encoding().unwrap()
+        );
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+        let body = block_on(response.into_body().collect()).unwrap().to_bytes();
+        assert_eq!(body, "hello from the server");
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_keeps_existing_accept_encoding() {
+        let service = DecompressionLayer::new().layer(tower::service_fn(server));
+        let req = Request::builder()
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(())
+            .unwrap();
+        let response = block_on(service.oneshot(req)).unwrap();
+
+        assert_eq!(response.headers()["x-accept-encoding"], "gzip");
+        let body = block_on(response.into_body().collect()).unwrap().to_bytes();
+        assert_eq!(body, "hello from the server");
+    }
+
+    #[test]
+    fn test_unsupported_encoding_passes_through() {
+        let service = DecompressionLayer::new().layer(tower::service_fn(|_: Request<()>| async {
+            let mut response = Response::new(Full::new(Bytes::from("compressed")));
+            response.headers_mut().insert(
+                header::CONTENT_ENCODING,
+                HeaderValue::from_static("compress"),
+            );
+            Ok::<_, Infallible>(response)
+        }));
+        let response = block_on(service.oneshot(Request::new(()))).unwrap();
+
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "compress");
+        let body = block_on(response.into_body().collect()).unwrap().to_bytes();
+        assert_eq!(body, "compressed");
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_head_response_passes_through() {
+        let service = DecompressionLayer::new().layer(tower::service_fn(|_: Request<()>| async {
+            let mut response = Response::new(Full::new(Bytes::new()));
+            response
+                .headers_mut()
+                .insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+            Ok::<_, Infallible>(response)
+        }));
+        let req = Request::head("/").body(()).unwrap();
+        let response = block_on(service.oneshot(req)).unwrap();
+
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        let body = block_on(response.into_body().collect()).unwrap().to_bytes();
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_trailers_are_preserved() {
+        let service = DecompressionLayer::new().layer(tower::service_fn(|_: Request<()>| async {
+            let body = crate::CompressionBody::compressed(
+                Full::new(Bytes::from("with trailers")),
+                Codec::Gzip,
+                false,
+            );
+            let mut trailers = http::HeaderMap::new();
+            trailers.insert("x-checksum", HeaderValue::from_static("abc123"));
+            let body = body.with_trailers(async move { Some(Ok(trailers)) });
+
+            let mut response = Response::new(body);
+            response
+                .headers_mut()
+                .insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+            Ok::<_, Infallible>(response)
+        }));
+        let response = block_on(service.oneshot(Request::new(()))).unwrap();
+
+        let collected = block_on(response.into_body().collect()).unwrap();
+        assert_eq!(collected.trailers().unwrap()["x-checksum"], "abc123");
+        assert_eq!(collected.to_bytes(), "with trailers");
+    }
+}
@@ -0,0 +1,499 @@
+use crate::codec::{Codec, CodecPreference};
+use crate::error::DecompressionError;
+use bytes::{Buf, Bytes};
+use compression_codecs::DecodeV2;
+use compression_core::util::{PartialBuffer, WriteBuffer};
+use http::{HeaderMap, header};
+use http_body::{Body, Frame};
+use pin_project_lite::pin_project;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+const OUTPUT_BUFFER_SIZE: usize = 8 * 1024; // 8KB output buffer
+
+/// Output size below which the expansion ratio isn't enforced, so small but
+/// highly compressible bodies aren't rejected.
+const RATIO_GRACE_SIZE: u64 = 64 * 1024;
+
+/// Limits protecting against decompression bombs.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct DecompressionLimits {
+    /// Maximum decompressed size in bytes.
+    pub(crate) max_size: Option<u64>,
+    /// Maximum ratio of decompressed to compressed size.
+    pub(crate) max_ratio: Option<u64>,
+}
+
+impl DecompressionLimits {
+    /// Checks the amount of input consumed and output produced so far.
+    fn check(&self, input: u64, output: u64) -> Result<(), DecompressionError> {
+        if let Some(limit) = self.max_size
+            && output > limit
+        {
+            return Err(DecompressionError::SizeLimitExceeded { limit });
+        }
+        if let Some(limit) = self.max_ratio
+            && output > RATIO_GRACE_SIZE
+            && output > input.saturating_mul(limit)
+        {
+            return Err(DecompressionError::RatioLimitExceeded { limit });
+        }
+        Ok(())
+    }
+}
+
+pin_project! {
+    /// A body that may be decompressed.
+    ///
+    /// This type wraps an inner body and either decodes it using the
+    /// specified codec or passes it through unchanged.
+    #[project = DecompressionBodyProj]
+    #[allow(missing_docs)]
+    pub enum DecompressionBody<B> {
+        /// Decompressed body with decoder.
+        Decompressed {
+            #[pin]
+            inner: B,
+            state: DecompressedBody,
+        },
+        /// Passthrough body without decompression.
+        Passthrough {
+            #[pin]
+            inner: B,
+        },
+    }
+}
+
+/// State and buffers for an actively decompressed body.
+pub(crate) struct DecompressedBody {
+    decoder: Box<dyn DecodeV2 + Send>,
+    output_buffer: Vec<u8>,
+    state: DecompressState,
+    /// Input read from the inner body that the decoder hasn't consumed yet.
+    pending_input: Bytes,
+    /// Whether the decoder reached the end of the compressed stream.
+    stream_done: bool,
+    pending_trailers: Option<http::HeaderMap>,
+    limits: DecompressionLimits,
+    /// Compressed bytes consumed so far.
+    total_in: u64,
+    /// Decompressed bytes produced so far.
+    total_out: u64,
+}
+
+/// State machine for decompression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DecompressState {
+    /// Reading data from inner body and decoding.
+    Reading,
+    /// Finishing decoding after inner body is done.
+    Finishing,
+    /// Emitting buffered trailers.
+    Trailers,
+    /// Decompression is complete.
+    Done,
+}
+
+impl DecompressedBody {
+    /// Creates a new decompressed body state with the given codec and limits.
+    fn new(codec: Codec, limits: DecompressionLimits) -> Self {
+        Self {
+            decoder: codec.decoder(),
+            output_buffer: vec![0u8; OUTPUT_BUFFER_SIZE],
+            state: DecompressState::Reading,
+            pending_input: Bytes::new(),
+            stream_done: false,
+            pending_trailers: None,
+            limits,
+            total_in: 0,
+            total_out: 0,
+        }
+    }
+
+    /// Records decoder output, checking it against the limits.
+    fn output(&mut self, written: usize) -> Result<Option<Bytes>, DecompressionError> {
+        if written == 0 {
+            return Ok(None);
+        }
+        self.total_out += written as u64;
+        self.limits.check(self.total_in, self.total_out)?;
+        Ok(Some(Bytes::copy_from_slice(&self.output_buffer[..written])))
+    }
+
+    /// Returns the state to move to once decoding is complete.
+    fn end_state(&self) -> DecompressState {
+        if self.pending_trailers.is_some() {
+            DecompressState::Trailers
+        } else {
+            DecompressState::Done
+        }
+    }
+
+    /// Polls the inner body and decodes data.
+    fn poll_decompressed<B>(
+        &mut self,
+        cx: &mut Context<'_>,
+        mut inner: Pin<&mut B>,
+    ) -> Poll<Option<Result<Frame<Bytes>, DecompressionError>>>
+    where
+        B: Body,
+        B::Data: Buf,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        loop {
+            match self.state {
+                DecompressState::Done => return Poll::Ready(None),
+
+                DecompressState::Trailers => {
+                    self.state = DecompressState::Done;
+                    if let Some(trailers) = self.pending_trailers.take() {
+                        return Poll::Ready(Some(Ok(Frame::trailers(trailers))));
+                    }
+                    return Poll::Ready(None);
+                }
+
+                DecompressState::Finishing => {
+                    let mut output =
+                        WriteBuffer::new_initialized(self.output_buffer.as_mut_slice());
+
+                    let result = self.decoder.finish(&mut output);
+                    let written = output.written_len();
+                    let result = result
+                        .map_err(DecompressionError::InvalidData)
+                        .and_then(|done| Ok((done, self.output(written)?)));
+                    match result {
+                        Ok((done, data)) => {
+                            if done {
+                                self.state = self.end_state();
+                            }
+                            if let Some(data) = data {
+                                return Poll::Ready(Some(Ok(Frame::data(data))));
+                            }
+                        }
+                        Err(e) => {
+                            self.state = DecompressState::Done;
+                            return Poll::Ready(Some(Err(e)));
+                        }
+                    }
+                }
+
+                DecompressState::Reading if !self.pending_input.is_empty() => {
+                    match self.decode_pending() {
+                        Ok(Some(output)) => return Poll::Ready(Some(Ok(Frame::data(output)))),
+                        Ok(None) => continue,
+                        Err(e) => {
+                            self.state = DecompressState::Done;
+                            return Poll::Ready(Some(Err(e)));
+                        }
+                    }
+                }
+
+                DecompressState::Reading => match inner.as_mut().poll_frame(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(None) => {
+                        self.state = DecompressState::Finishing;
+                    }
+                    Poll::Ready(Some(Err(e))) => {
+                        return Poll::Ready(Some(Err(DecompressionError::Body(e.into()))));
+                    }
+                    Poll::Ready(Some(Ok(frame))) => match frame.into_data() {
+                        Ok(mut data) => {
+                            self.pending_input = data.copy_to_bytes(data.remaining());
+                        }
+                        Err(frame) => {
+                            if let Ok(trailers) = frame.into_trailers() {
+                                // Buffer trailers and finish decoding first
+                                self.pending_trailers = Some(trailers);
+                                self.state = DecompressState::Finishing;
+                            }
+                        }
+                    },
+                },
+            }
+        }
+    }
+
+    /// Decodes pending input into at most one output buffer.
+    ///
+    /// Returns `None` if the decoder consumed input without producing any
+    /// output yet.
+    fn decode_pending(&mut self) -> Result<Option<Bytes>, DecompressionError> {
+        if self.stream_done {
+            // Another member or frame follows the end of the previous one
+            self.decoder
+                .reinit()
+                .map_err(DecompressionError::InvalidData)?;
+            self.stream_done = false;
+        }
+
+        let mut input = PartialBuffer::new(&self.pending_input[..]);
+        let mut output = WriteBuffer::new_initialized(self.output_buffer.as_mut_slice());
+
+        self.stream_done = self
+            .decoder
+            .decode(&mut input, &mut output)
+            .map_err(DecompressionError::InvalidData)?;
+
+        let consumed = input.written_len();
+        let written = output.written_len();
+        if consumed == 0 && written == 0 && !self.stream_done {
+            return Err(DecompressionError::InvalidData(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "decoder made no progress",
+            )));
+        }
+        self.pending_input.advance(consumed);
+        self.total_in += consumed as u64;
+
+        self.output(written)
+    }
+}
+
+/// Returns the codec a body is encoded with according to its `Content-Encoding`
+/// headers, `None` if it isn't encoded, or an error if the encoding isn't
+/// supported.
+///
+/// Only a single coding is supported; `identity` codings are ignored.
+pub(crate) fn content_coding(
+    headers: &HeaderMap,
+    codecs: &CodecPreference,
+) -> Result<Option<Codec>, ()> {
+    let mut codec = None;
+    for value in headers.get_all(header::CONTENT_ENCODING) {
+        let value = value.to_str().map_err(|_| ())?;
+        for coding in value.split(',').map(str::trim) {
+            if coding.is_empty() || coding.eq_ignore_ascii_case("identity") {
+                continue;
+            }
+            match Codec::from_content_encoding(coding) {
+                Some(found) if codec.is_none() && codecs.is_enabled(found) => codec = Some(found),
+                _ => return Err(()),
+            }
+        }
+    }
+    Ok(codec)
+}
+
+impl<B> DecompressionBody<B> {
+    /// Creates a body decoded with the given codec, without size limits.
+    pub fn decompressed(inner: B, codec: Codec) -> Self {
+        Self::with_limits(inner, codec, DecompressionLimits::default())
+    }
+
+    /// Creates a body decoded with the given codec and limits.
+    pub(crate) fn with_limits(inner: B, codec: Codec, limits: DecompressionLimits) -> Self {
+        Self::Decompressed {
+            inner,
+            state: DecompressedBody::new(codec, limits),
+        }
+    }
+
+    /// Creates a passthrough body without decompression.
+    pub fn passthrough(inner: B) -> Self {
+        Self::Passthrough { inner }
+    }
+}
+
+impl<B> Body for DecompressionBody<B>
+where
+    B: Body,
+    B::Data: Buf,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    type Data = Bytes;
+    type Error = DecompressionError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        match self.project() {
+            DecompressionBodyProj::Passthrough { inner } => inner
+                .poll_frame(cx)
+                .map_ok(|frame| frame.map_data(|mut data| data.copy_to_bytes(data.remaining())))
+                .map_err(|e| DecompressionError::Body(e.into())),
+            DecompressionBodyProj::Decompressed { inner, state } => {
+                state.poll_decompressed(cx, inner)
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        match self {
+            DecompressionBody::Passthrough { inner } => inner.is_end_stream(),
+            DecompressionBody::Decompressed { state, .. } => state.state == DecompressState::Done,
+        }
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        match self {
+            DecompressionBody::Passthrough { inner } => inner.size_hint(),
+            // Decompressed size is unknown
+            DecompressionBody::Decompressed { .. } => http_body::SizeHint::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body::CompressionBody;
+    use http_body_util::{BodyExt, Full};
+
+    /// Collects a body that is always ready.
+    fn collect<B>(body: B) -> Result<(Bytes, Option<HeaderMap>), B::Error>
+    where
+        B: Body,
+    {
+        let mut collect = std::pin::pin!(body.collect());
+        let mut cx = Context::from_waker(std::task::Waker::noop());
+        match collect.as_mut().poll(&mut cx) {
+            Poll::Ready(collected) => collected.map(|collected| {
+                let trailers = collected.trailers().cloned();
+                (collected.to_bytes(), trailers)
+            }),
+            Poll::Pending => panic!("body is not ready"),
+        }
+    }
+
+    /// Compresses data with the codec.
+    fn compress(codec: Codec, data: &[u8]) -> Bytes {
+        let body =
+            CompressionBody::compressed(Full::new(Bytes::copy_from_slice(data)), codec, false);
+        collect(body).unwrap().0
+    }
+
+    /// A body yielding the given frames.
+    struct Frames(std::collections::VecDeque<Frame<Bytes>>);
+
+    impl Body for Frames {
+        type Data = Bytes;
+        type Error = std::convert::Infallible;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+            Poll::Ready(self.0.pop_front().map(Ok))
+        }
+    }
+
+    #[test]
+    fn test_passthrough() {
+        let body = DecompressionBody::passthrough(Full::new(Bytes::from("hello world")));
+        assert_eq!(collect(body).unwrap().0, "hello world");
+    }
+
+    #[test]
+    fn test_round_trip_every_codec() {
+        let input = "hello decompression ".repeat(2000);
+        for codec in crate::codec::CodecPreference::default().iter() {
+            let compressed = compress(codec, input.as_bytes());
+            let body = DecompressionBody::decompressed(Full::new(compressed), codec);
+            let (data, _) = collect(body).unwrap();
+            assert_eq!(data, input.as_bytes(), "{codec:?}");
+        }
+    }
+
+    #[test]
+    fn test_split_chunks_with_trailers() {
+        let input = "split across many small chunks ".repeat(100);
+        let mut trailers = HeaderMap::new();
+        trailers.insert("x-checksum", "abc123".parse().unwrap());
+
+        for codec in crate::codec::CodecPreference::default().iter() {
+            let compressed = compress(codec, input.as_bytes());
+            let mut chunks: Vec<_> = compressed
+                .chunks(7)
+                .map(|chunk| Frame::data(Bytes::copy_from_slice(chunk)))
+                .collect();
+            chunks.push(Frame::trailers(trailers.clone()));
+
+            let body = DecompressionBody::decompressed(Frames(chunks.into()), codec);
+            let (data, received) = collect(body).unwrap();
+            assert_eq!(data, input.as_bytes(), "{codec:?}");
+            assert_eq!(received.unwrap().get("x-checksum").unwrap(), "abc123");
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_concatenated_members() {
+        let mut compressed = compress(Codec::Gzip, b"hello ").to_vec();
+        compressed.extend_from_slice(&compress(Codec::Gzip, b"world"));
+
+        let body = DecompressionBody::decompressed(Full::new(Bytes::from(compressed)), Codec::Gzip);
+        assert_eq!(collect(body).unwrap().0, "hello world");
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_invalid_data() {
+        let body =
+            DecompressionBody::decompressed(Full::new(Bytes::from("not gzip at all")), Codec::Gzip);
+        let err = collect(body).unwrap_err();
+        assert!(matches!(err, DecompressionError::InvalidData(_)));
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let input = vec![0u8; 1024 * 1024];
+        for codec in crate::codec::CodecPreference::default().iter() {
+            let compressed = compress(codec, &input);
+
+            let limits = DecompressionLimits {
+                max_size: Some(100_000),
+                max_ratio: None,
+            };
+            let body = DecompressionBody::with_limits(Full::new(compressed.clone()), codec, limits);
+            let err = collect(body).unwrap_err();
+            assert!(
+                matches!(
+                    err,
+                    DecompressionError::SizeLimitExceeded { limit: 100_000 }
+                ),
+                "{codec:?}: {err}"
+            );
+
+            let limits = DecompressionLimits {
+                max_size: Some(input.len() as u64),
+                max_ratio: None,
+            };
+            let body = DecompressionBody::with_limits(Full::new(compressed), codec, limits);
+            assert_eq!(collect(body).unwrap().0.len(), input.len(), "{codec:?}");
+        }
+    }
+
+    #[test]
+    fn test_ratio_limit() {
+        let limits = DecompressionLimits {
+            max_size: None,
+            max_ratio: Some(10),
+        };
+        for codec in crate::codec::CodecPreference::default().iter() {
+            let compressed = compress(codec, &vec![0u8; 1024 * 1024]);
+            let body = DecompressionBody::with_limits(Full::new(compressed), codec, limits);
+            let err = collect(body).unwrap_err();
+            assert!(
+                matches!(err, DecompressionError::RatioLimitExceeded { limit: 10 }),
+                "{codec:?}: {err}"
+            );
+
+            // Small outputs are allowed regardless of their ratio
+            let compressed = compress(codec, &[0u8; 1024]);
+            let body = DecompressionBody::with_limits(Full::new(compressed), codec, limits);
+            assert_eq!(collect(body).unwrap().0.len(), 1024, "{codec:?}");
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_truncated_data() {
+        let compressed = compress(Codec::Gzip, "truncated".repeat(100).as_bytes());
+        let truncated = compressed.slice(..compressed.len() / 2);
+
+        let body = DecompressionBody::decompressed(Full::new(truncated), Codec::Gzip);
+        assert!(collect(body).is_err());
+    }
+}
@@ -0,0 +1,394 @@
+use crate::accept_encoding::AcceptEncoding;
+use crate::codec::Codec;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use bytes::Bytes;
+#[cfg(any(feature = "zstd", feature = "brotli"))]
+use compression_codecs::EncodeV2;
+#[cfg(any(feature = "zstd", feature = "brotli"))]
+use compression_core::util::{PartialBuffer, WriteBuffer};
+use http::{HeaderMap, HeaderValue};
+use sha2::{Digest, Sha256};
+use std::fmt;
+#[cfg(any(feature = "zstd", feature = "brotli"))]
+use std::io;
+use std::sync::Arc;
+
+/// Request header carrying the hash of the dictionary the client has.
+const AVAILABLE_DICTIONARY: &str = "available-dictionary";
+
+/// Response header marking a response as a dictionary for later requests.
+pub(crate) const USE_AS_DICTIONARY: &str = "use-as-dictionary";
+
+/// Magic number starting a `dcz` stream.
+#[cfg(feature = "zstd")]
+pub(crate) const DCZ_MAGIC: &[u8] = &[0x5e, 0x2a, 0x4d, 0x18, 0x20, 0x00, 0x00, 0x00];
+
+/// Magic number starting a `dcb` stream.
+#[cfg(feature = "brotli")]
+pub(crate) const DCB_MAGIC: &[u8] = &[0xff, 0x44, 0x43, 0x42];
+
+/// A shared dictionary for Compression Dictionary Transport (RFC 9842).
+///
+/// The dictionary is the body of a resource served at `path`, which is sent
+/// with a `Use-As-Dictionary` header so clients store it for later requests
+/// to URLs matching `pattern`. When such a request carries the dictionary's
+/// hash in `Available-Dictionary` and accepts `dcz` or `dcb`, the response is
+/// compressed with the dictionary, usually to a fraction of its regular
+/// compressed size.
+///
+/// The bytes must be exactly the body the client received for `path`, after
+/// removing any content coding, or clients won't advertise a matching hash.
+///
+/// ```ignore
+/// use http_response_compression::{CompressionLayer, Dictionary};
+///
+/// let layer = CompressionLayer::new().dictionary(Dictionary::new(
+///     "/static/app.v1.js",
+///     "/static/app.*.js",
+///     std::fs::read("dist/static/app.v1.js")?,
+/// ));
+/// ```
+#[derive(Clone)]
+pub struct Dictionary {
+    path: String,
+    pattern: String,
+    id: Option<String>,
+    bytes: Bytes,
+    hash: [u8; 32],
+    // The hash as a structured field byte sequence, like `:base64:`
+    available: String,
+}
+
+impl Dictionary {
+    /// Creates a dictionary served at `path` and used for URLs matching the
+    /// URL pattern `pattern`.
+    pub fn new(
+        path: impl Into<String>,
+        pattern: impl Into<String>,
+        bytes: impl Into<Bytes>,
+    ) -> Self {
+        let bytes = bytes.into();
+        let hash: [u8; 32] = Sha256::digest(&bytes).into();
+        Self {
+            path: path.into(),
+            pattern: pattern.into(),
+            id: None,
+            bytes,
+            hash,
+            available: format!(":{}:", STANDARD.encode(hash)),
+        }
+    }
+
+    /// Sets the identifier clients echo back in the `Dictionary-ID` header.
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Returns the path the dictionary is served at.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Returns the URL pattern the dictionary is used for.
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Returns the dictionary's contents.
+    pub fn bytes(&self) -> &Bytes {
+        &self.bytes
+    }
+
+    /// Returns the SHA-256 hash of the dictionary's contents.
+    pub fn hash(&self) -> &[u8; 32] {
+        &self.hash
+    }
+
+    /// Returns the `Use-As-Dictionary` header value announcing this
+    /// dictionary, or `None` if the pattern or id can't be represented in a
+    /// header.
+    pub(crate) fn use_as_dictionary(&self) -> Option<HeaderValue> {
+        let mut value = format!("match={}", sf_string(&self.pattern)?);
+        if let Some(id) = &self.id {
+            value.push_str(&format!(", id={}", sf_string(id)?));
+        }
+        HeaderValue::from_str(&value).ok()
+    }
+
+    /// Wraps an encoder so its output starts with the header of a
+    /// dictionary-compressed stream.
+    #[cfg(any(feature = "zstd", feature = "brotli"))]
+    pub(crate) fn framed(
+        &self,
+        magic: &[u8],
+        inner: Box<dyn EncodeV2 + Send>,
+    ) -> Box<dyn EncodeV2 + Send> {
+        let mut header = magic.to_vec();
+        header.extend_from_slice(&self.hash);
+        Box::new(FramedEncoder {
+            header: PartialBuffer::new(header),
+            inner,
+        })
+    }
+}
+
+impl fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dictionary")
+            .field("path", &self.path)
+            .field("pattern", &self.pattern)
+            .field("id", &self.id)
+            .field("len", &self.bytes.len())
+            .finish()
+    }
+}
+
+/// Finds the registered dictionary the client has, along with the first of
+/// the codecs whose dictionary-compressed coding it accepts.
+///
+/// Dictionary-compressed codings must be listed explicitly in
+/// `Accept-Encoding`; a `*` wildcard doesn't enable them.
+pub(crate) fn negotiate(
+    dictionaries: &[Arc<Dictionary>],
+    headers: &HeaderMap,
+    codecs: impl IntoIterator<Item = Codec>,
+) -> Option<(Codec, Arc<Dictionary>)> {
+    if dictionaries.is_empty() {
+        return None;
+    }
+    let available = headers.get(AVAILABLE_DICTIONARY)?.to_str().ok()?.trim();
+    let dictionary = dictionaries.iter().find(|d| d.available == available)?;
+
+    let accept = AcceptEncoding::from_headers(headers);
+    let codec = codecs.into_iter().find(|codec| {
+        codec
+            .dictionary_encoding()
+            .is_some_and(|coding| accept.accepts(coding))
+    })?;
+    Some((codec, dictionary.clone()))
+}
+
+/// Encoder writing a stream header before the output of its inner encoder.
+#[cfg(any(feature = "zstd", feature = "brotli"))]
+struct FramedEncoder {
+    header: PartialBuffer<Vec<u8>>,
+    inner: Box<dyn EncodeV2 + Send>,
+}
+
+#[cfg(any(feature = "zstd", feature = "brotli"))]
+impl FramedEncoder {
+    /// Writes as much of the header as fits, returning whether it is complete.
+    fn write_header(&mut self, output: &mut WriteBuffer<'_>) -> bool {
+        output.copy_unwritten_from(&mut self.header);
+        self.header.unwritten().is_empty()
+    }
+}
+
+#[cfg(any(feature = "zstd", feature = "brotli"))]
+impl EncodeV2 for FramedEncoder {
+    fn encode(
+        &mut self,
+        input: &mut PartialBuffer<&[u8]>,
+        output: &mut WriteBuffer<'_>,
+    ) -> io::Result<()> {
+        if self.write_header(output) {
+            self.inner.encode(input, output)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, output: &mut WriteBuffer<'_>) -> io::Result<bool> {
+        if !self.write_header(output) {
+            return Ok(false);
+        }
+        self.inner.flush(output)
+    }
+
+    fn finish(&mut self, output: &mut WriteBuffer<'_>) -> io::Result<bool> {
+        if !self.write_header(output) {
+            return Ok(false);
+        }
+        self.inner.finish(output)
+    }
+}
+
+/// Brotli encoder using a shared dictionary, which the brotli encoder of
+/// `compression-codecs` doesn't support.
+#[cfg(feature = "brotli")]
+pub(crate) struct BrotliDictionaryEncoder {
+    state: brotli::enc::encode::BrotliEncoderStateStruct<brotli::enc::StandardAlloc>,
+}
+
+#[cfg(feature = "brotli")]
+impl BrotliDictionaryEncoder {
+    /// Creates an encoder with the given quality and dictionary.
+    pub(crate) fn new(level: compression_core::Level, dictionary: &[u8]) -> Self {
+        use compression_codecs::brotli::params::EncoderParams;
+
+        let mut state = brotli::enc::encode::BrotliEncoderStateStruct::new(
+            brotli::enc::StandardAlloc::default(),
+        );
+        state.params = EncoderParams::default().quality(level).into();
+        // At quality 11 the encoder can reference the dictionary in ways the
+        // brotli decoder rejects, so the highest usable quality is 10
+        state.params.quality = state.params.quality.min(10);
+        state.set_custom_dictionary(dictionary.len(), dictionary);
+        Self { state }
+    }
+
+    fn compress(
+        &mut self,
+        input: &mut PartialBuffer<&[u8]>,
+        output: &mut WriteBuffer<'_>,
+        op: brotli::enc::encode::BrotliEncoderOperation,
+    ) -> io::Result<()> {
+        let in_buf = input.unwritten();
+        let out_buf = output.initialize_unwritten();
+        let mut input_len = 0;
+        let mut output_len = 0;
+
+        if !self.state.compress_stream(
+            op,
+            &mut in_buf.len(),
+            in_buf,
+            &mut input_len,
+            &mut out_buf.len(),
+            out_buf,
+            &mut output_len,
+            &mut None,
+            &mut |_, _, _, _| (),
+        ) {
+            return Err(io::Error::other("brotli error"));
+        }
+
+        input.advance(input_len);
+        output.advance(output_len);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "brotli")]
+impl EncodeV2 for BrotliDictionaryEncoder {
+    fn encode(
+        &mut self,
+        input: &mut PartialBuffer<&[u8]>,
+        output: &mut WriteBuffer<'_>,
+    ) -> io::Result<()> {
+        use brotli::enc::encode::BrotliEncoderOperation;
+
+        self.compress(
+            input,
+            output,
+            BrotliEncoderOperation::BROTLI_OPERATION_PROCESS,
+        )
+    }
+
+    fn flush(&mut self, output: &mut WriteBuffer<'_>) -> io::Result<bool> {
+        use brotli::enc::encode::BrotliEncoderOperation;
+
+        let mut input = PartialBuffer::new(&[][..]);
+        self.compress(
+            &mut input,
+            output,
+            BrotliEncoderOperation::BROTLI_OPERATION_FLUSH,
+        )?;
+        Ok(!self.state.has_more_output())
+    }
+
+    fn finish(&mut self, output: &mut WriteBuffer<'_>) -> io::Result<bool> {
+        use brotli::enc::encode::BrotliEncoderOperation;
+
+        let mut input = PartialBuffer::new(&[][..]);
+        self.compress(
+            &mut input,
+            output,
+            BrotliEncoderOperation::BROTLI_OPERATION_FINISH,
+        )?;
+        Ok(self.state.is_finished())
+    }
+}
+
+/// Serializes a structured field string, or returns `None` if the value has
+/// characters a string can't hold.
+fn sf_string(value: &str) -> Option<String> {
+    let mut serialized = String::with_capacity(value.len() + 2);
+    serialized.push('"');
+    for c in value.chars() {
+        if !(' '..='~').contains(&c) {
+            return None;
+        }
+        if c == '"' || c == '\\' {
+            serialized.push('\\');
+        }
+        serialized.push(c);
+    }
+    serialized.push('"');
+    Some(serialized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::CodecPreference;
+
+    #[test]
+    fn test_use_as_dictionary() {
+        let dictionary = Dictionary::new("/app.v1.js", "/app.*.js", "dictionary");
+        assert_eq!(
+            dictionary.use_as_dictionary().unwrap(),
+            "match=\"/app.*.js\""
+        );
+
+        let dictionary = dictionary.id("app \"v1\"");
+        assert_eq!(
+            dictionary.use_as_dictionary().unwrap(),
+            "match=\"/app.*.js\", id=\"app \\\"v1\\\"\""
+        );
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let dictionary = Arc::new(Dictionary::new("/d", "/*", "dictionary"));
+        let dictionaries = [dictionary.clone()];
+        let preference = CodecPreference::default();
+        let headers = |accept: &'static str, available: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                http::header::ACCEPT_ENCODING,
+                HeaderValue::from_static(accept),
+            );
+            headers.insert(
+                AVAILABLE_DICTIONARY,
+                HeaderValue::from_str(available).unwrap(),
+            );
+            headers
+        };
+
+        let available = dictionary.available.clone();
+        let expected = preference
+            .iter()
+            .find(|c| c.dictionary_encoding().is_some());
+        let negotiated = negotiate(
+            &dictionaries,
+            &headers("gzip, br, zstd, dcb, dcz", &available),
+            preference.iter(),
+        );
+        assert_eq!(negotiated.map(|(codec, _)| codec), expected);
+
+        // Unknown dictionary, wildcard and refused codings
+        for (accept, available) in [
+            ("dcb, dcz", ":AAAA:"),
+            ("*", available.as_str()),
+            ("dcb;q=0, dcz;q=0", available.as_str()),
+        ] {
+            let negotiated = negotiate(
+                &dictionaries,
+                &headers(accept, available),
+                preference.iter(),
+            );
+            assert!(negotiated.is_none(), "{accept} {available}");
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// An error from reading a [`DecompressionBody`].
+///
+/// [`DecompressionBody`]: crate::DecompressionBody
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum DecompressionError {
+    /// The decompressed body grew beyond the maximum size, in bytes.
+    SizeLimitExceeded {
+        /// The configured maximum size.
+        limit: u64,
+    },
+    /// The decompressed body grew beyond the maximum expansion ratio.
+    RatioLimitExceeded {
+        /// The configured maximum ratio of decompressed to compressed size.
+        limit: u64,
+    },
+    /// The body isn't valid for its content coding.
+    InvalidData(io::Error),
+    /// The inner body returned an error.
+    Body(Box<dyn Error + Send + Sync>),
+}
+
+impl fmt::Display for DecompressionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecompressionError::SizeLimitExceeded { limit } => {
+                write!(f, "decompressed body exceeds the limit of {limit} bytes")
+            }
+            DecompressionError::RatioLimitExceeded { limit } => {
+                write!(
+                    f,
+                    "decompressed body exceeds the expansion ratio of {limit}"
+                )
+            }
+            DecompressionError::InvalidData(_) => f.write_str("invalid compressed data"),
+            DecompressionError::Body(_) => f.write_str("error reading body"),
+        }
+    }
+}
+
+impl Error for DecompressionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DecompressionError::InvalidData(e) => Some(e),
+            DecompressionError::Body(e) => Some(&**e),
+            DecompressionError::SizeLimitExceeded { .. }
+            | DecompressionError::RatioLimitExceeded { .. } => None,
+        }
+    }
+}
+
+impl From<DecompressionError> for io::Error {
+    fn from(e: DecompressionError) -> Self {
+        let kind = match e {
+            DecompressionError::InvalidData(_)
+            | DecompressionError::SizeLimitExceeded { .. }
+            | DecompressionError::RatioLimitExceeded { .. } => io::ErrorKind::InvalidData,
+            DecompressionError::Body(_) => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, e)
+    }
+}
@@ -0,0 +1,329 @@
+use crate::codec::CodecPreference;
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+
+/// How the `ETag` of a compressed response is rewritten.
+///
+/// An entity tag identifies a single representation, and the compressed body
+/// is a different representation than the one the inner service tagged. The
+/// request's conditional headers are rewritten to match, so the inner
+/// service's `If-None-Match` and `If-Match` handling keeps working with the
+/// tags it produced, and a `304 Not Modified` gets the rewritten tag back.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ETagMode {
+    /// Leaves the `ETag` unchanged.
+    #[default]
+    Keep,
+    /// Makes a strong `ETag` weak, turning `"abc"` into `W/"abc"`.
+    ///
+    /// `If-None-Match` tags are made strong again, which doesn't change how
+    /// they compare, as `If-None-Match` uses weak comparison.
+    Weaken,
+    /// Appends the content coding to the `ETag`, turning `"abc"` into
+    /// `"abc-gzip"`, or `"abc-dcz"` when compressed with a shared dictionary.
+    ///
+    /// The suffix is removed from `If-None-Match` and `If-Match` tags.
+    Suffix,
+}
+
+/// Tags of compressed representations a conditional request referred to, as
+/// the opaque tag of the uncompressed representation they were rewritten to,
+/// and the content coding of the compressed one.
+pub(crate) type Validated = Vec<(String, &'static str)>;
+
+impl ETagMode {
+    /// Rewrites the `ETag` of a response compressed with the content coding.
+    pub(crate) fn rewrite_response(self, headers: &mut HeaderMap, coding: &str) {
+        let Some((weak, opaque)) = headers
+            .get(header::ETAG)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| parse_tag(v.trim()))
+        else {
+            return;
+        };
+        let rewritten = match self {
+            ETagMode::Keep => return,
+            ETagMode::Weaken if weak => return,
+            ETagMode::Weaken => format_tag(true, opaque),
+            ETagMode::Suffix => format_tag(weak, &format!("{opaque}-{coding}")),
+        };
+        if let Ok(value) = HeaderValue::from_str(&rewritten) {
+            headers.insert(header::ETAG, value);
+        }
+    }
+
+    /// Rewrites the `ETag` of a `304 Not Modified` response, if it validated
+    /// one of the compressed representations the request referred to.
+    pub(crate) fn rewrite_not_modified(self, headers: &mut HeaderMap, validated: &Validated) {
+        let coding = headers
+            .get(header::ETAG)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| parse_tag(v.trim()))
+            .and_then(|(weak, opaque)| {
+                // Weak tags of the inner service weren't weakened by this layer
+                if weak && self == ETagMode::Weaken {
+                    return None;
+                }
+                validated
+                    .iter()
+                    .find(|(tag, _)| tag == opaque)
+                    .map(|(_, coding)| *coding)
+            });
+        if let Some(coding) = coding {
+            self.rewrite_response(headers, coding);
+        }
+    }
+
+    /// Rewrites the conditional headers of a request to the tags of the
+    /// uncompressed representation.
+    ///
+    /// Returns the rewritten tags, so a `304 Not Modified` validating one of
+    /// them can be given the compressed representation's `ETag` back.
+    ///
+    /// With [`ETagMode::Weaken`], a weak tag the inner service issued itself
+    /// is indistinguishable from one this layer weakened, so it is made
+    /// strong too. Weak comparison ignores the difference, and the `304` is
+    /// only rewritten if the inner service's tag is strong.
+    pub(crate) fn normalize_request(
+        self,
+        headers: &mut HeaderMap,
+        preference: &CodecPreference,
+        accepted_coding: Option<&'static str>,
+    ) -> Validated {
+        let mut validated = Validated::new();
+        match self {
+            ETagMode::Keep => {}
+            ETagMode::Weaken => {
+                rewrite_header(headers, header::IF_NONE_MATCH, |weak, opaque| {
+                    if !weak {
+                        return None;
+                    }
+                    if let Some(coding) = accepted_coding {
+                        validated.push((opaque.to_owned(), coding));
+                    }
+                    Some(format_tag(false, opaque))
+                });
+            }
+            ETagMode::Suffix => {
+                for name in [header::IF_NONE_MATCH, header::IF_MATCH] {
+                    rewrite_header(headers, name, |weak, opaque| {
+                        let (coding, opaque) = preference
+                            .iter()
+                            .flat_map(|codec| {
+                                [Some(codec.content_encoding()), codec.dictionary_encoding()]
+                            })
+                            .flatten()
+                            .find_map(|coding| {
+                                let opaque = opaque.strip_suffix(coding)?.strip_suffix('-')?;
+                                Some((coding, opaque))
+                            })?;
+                        validated.push((opaque.to_owned(), coding));
+                        Some(format_tag(weak, opaque))
+                    });
+                }
+            }
+        }
+        validated
+    }
+}
+
+/// Rewrites every entity tag in a header, keeping the tags for which the
+/// function returns `None`.
+fn rewrite_header<F>(headers: &mut HeaderMap, name: HeaderName, mut f: F)
+where
+    F: FnMut(bool, &str) -> Option<String>,
+{
+    let mut changed = false;
+    let values: Vec<HeaderValue> = headers
+        .get_all(&name)
+        .iter()
+        .map(|value| {
+            let rewritten = value
+                .to_str()
+                .ok()
+                .and_then(|list| rewrite_list(list, &mut f))
+                .and_then(|list| HeaderValue::from_str(&list).ok());
+            changed |= rewritten.is_some();
+            rewritten.unwrap_or_else(|| value.clone())
+        })
+        .collect();
+
+    if changed {
+        headers.remove(&name);
+        for value in values {
+            headers.append(&name, value);
+        }
+    }
+}
+
+/// Rewrites a comma-separated list of entity tags.
+///
+/// Returns `None` if no tag changed, or if the list is `*` or malformed.
+fn rewrite_list<F>(list: &str, f: &mut F) -> Option<String>
+where
+    F: FnMut(bool, &str) -> Option<String>,
+{
+    let mut rewritten = String::new();
+    let mut changed = false;
+    let mut rest = list;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_ascii_whitespace());
+        if rest.is_empty() {
+            break;
+        }
+        // Entity tags may contain commas, so the list can't simply be split
+        let (weak, tag) = match rest.strip_prefix("W/") {
+            Some(tag) => (true, tag),
+            None => (false, rest),
+        };
+        let tag = tag.strip_prefix('"')?;
+        let end = tag.find('"')?;
+        let opaque = &tag[..end];
+        rest = &tag[end + 1..];
+
+        if !rewritten.is_empty() {
+            rewritten.push_str(", ");
+        }
+        match f(weak, opaque) {
+            Some(tag) => {
+                changed = true;
+                rewritten.push_str(&tag);
+            }
+            None => rewritten.push_str(&format_tag(weak, opaque)),
+        }
+    }
+    changed.then_some(rewritten)
+}
+
+/// Splits an entity tag into its weakness and opaque tag.
+fn parse_tag(tag: &str) -> Option<(bool, &str)> {
+    let (weak, tag) = match tag.strip_prefix("W/") {
+        Some(tag) => (true, tag),
+        None => (false, tag),
+    };
+    let opaque = tag.strip_prefix('"')?.strip_suffix('"')?;
+    (!opaque.contains('"')).then_some((weak, opaque))
+}
+
+/// Formats an entity tag.
+fn format_tag(weak: bool, opaque: &str) -> String {
+    if weak {
+        format!("W/\"{opaque}\"")
+    } else {
+        format!("\"{opaque}\"")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Codec;
+
+    fn any_codec() -> Codec {
+        CodecPreference::default().iter().next().unwrap()
+    }
+
+    fn rewritten(mode: ETagMode, etag: &'static str) -> String {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ETAG, HeaderValue::from_static(etag));
+        mode.rewrite_response(&mut headers, any_codec().content_encoding());
+        headers[header::ETAG].to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn test_rewrite_response() {
+        let coding = any_codec().content_encoding();
+        assert_eq!(rewritten(ETagMode::Keep, "\"abc\""), "\"abc\"");
+        assert_eq!(rewritten(ETagMode::Weaken, "\"abc\""), "W/\"abc\"");
+        assert_eq!(rewritten(ETagMode::Weaken, "W/\"abc\""), "W/\"abc\"");
+        assert_eq!(
+            rewritten(ETagMode::Suffix, "\"abc\""),
+            format!("\"abc-{coding}\"")
+        );
+        assert_eq!(
+            rewritten(ETagMode::Suffix, "W/\"abc\""),
+            format!("W/\"abc-{coding}\"")
+        );
+        assert_eq!(rewritten(ETagMode::Suffix, "malformed"), "malformed");
+    }
+
+    #[test]
+    fn test_normalize_weakened_request() {
+        let coding = any_codec().content_encoding();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::IF_NONE_MATCH,
+            HeaderValue::from_static("W/\"a,b\", \"c\""),
+        );
+        headers.insert(header::IF_MATCH, HeaderValue::from_static("W/\"a\""));
+
+        let validated =
+            ETagMode::Weaken.normalize_request(&mut headers, &Default::default(), Some(coding));
+        assert_eq!(validated, [("a,b".to_owned(), coding)]);
+        assert_eq!(headers[header::IF_NONE_MATCH], "\"a,b\", \"c\"");
+        assert_eq!(headers[header::IF_MATCH], "W/\"a\"");
+    }
+
+    #[test]
+    fn test_rewrite_not_modified() {
+        let coding = any_codec().content_encoding();
+        let validated = vec![("abc".to_owned(), coding)];
+        let not_modified = |mode: ETagMode, etag| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ETAG, HeaderValue::from_static(etag));
+            mode.rewrite_not_modified(&mut headers, &validated);
+            headers[header::ETAG].to_str().unwrap().to_owned()
+        };
+
+        assert_eq!(not_modified(ETagMode::Weaken, "\"abc\""), "W/\"abc\"");
+        assert_eq!(
+            not_modified(ETagMode::Suffix, "W/\"abc\""),
+            format!("W/\"abc-{coding}\"")
+        );
+        // The inner service's own weak tag was never weakened by this layer
+        assert_eq!(not_modified(ETagMode::Weaken, "W/\"abc\""), "W/\"abc\"");
+        assert_eq!(not_modified(ETagMode::Weaken, "\"def\""), "\"def\"");
+        assert_eq!(not_modified(ETagMode::Suffix, "\"def\""), "\"def\"");
+    }
+
+    #[test]
+    fn test_normalize_suffixed_request() {
+        let coding = any_codec().content_encoding();
+        let suffixed = format!("\"abc-{coding}\", \"def\"");
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::IF_NONE_MATCH,
+            HeaderValue::from_str(&suffixed).unwrap(),
+        );
+        headers.insert(header::IF_MATCH, HeaderValue::from_static("*"));
+
+        let validated = ETagMode::Suffix.normalize_request(&mut headers, &Default::default(), None);
+        assert_eq!(validated, [("abc".to_owned(), coding)]);
+        assert_eq!(headers[header::IF_NONE_MATCH], "\"abc\", \"def\"");
+        assert_eq!(headers[header::IF_MATCH], "*");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"abc\""));
+        let validated = ETagMode::Suffix.normalize_request(&mut headers, &Default::default(), None);
+        assert!(validated.is_empty());
+        assert_eq!(headers[header::IF_NONE_MATCH], "\"abc\"");
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn test_dictionary_suffix() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ETAG, HeaderValue::from_static("\"abc\""));
+        ETagMode::Suffix.rewrite_response(&mut headers, "dcz");
+        assert_eq!(headers[header::ETAG], "\"abc-dcz\"");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::IF_NONE_MATCH,
+            HeaderValue::from_static("\"abc-dcz\""),
+        );
+        let validated = ETagMode::Suffix.normalize_request(&mut headers, &Default::default(), None);
+        assert_eq!(validated, [("abc".to_owned(), "dcz")]);
+        assert_eq!(headers[header::IF_NONE_MATCH], "\"abc\"");
+    }
+}
@@ -1,27 +1,99 @@
-use crate::body::CompressionBody;
-use crate::codec::Codec;
-use http::{Response, header};
+use crate::accept_encoding::AcceptEncoding;
+use crate::body::{CompressionBody, Replay};
+use crate::breach::NoCompression;
+use crate::cache::{CompressionCache, Recorder};
+use crate::codec::{Codec, CompressionLevel};
+use crate::dictionary::{Dictionary, USE_AS_DICTIONARY};
+use crate::etag::Validated;
+use crate::metrics::Observation;
+use crate::overrides::{CompressionOverride, CompressionPolicy};
+use crate::predicate::{
+    MinSize, NoContentEncoding, NoContentRange, Predicate, RequestInfo, ResponseInfo,
+};
+use crate::settings::Settings;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use compression_codecs::EncodeV2;
+use http::{Method, Response, StatusCode, header, response};
+use http_body::Body;
 use pin_project_lite::pin_project;
+use private::InnerResponse;
 use std::future::Future;
+use std::io;
 use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
 
 pin_project! {
     /// Future for compression service responses.
-    pub struct ResponseFuture<F> {
+    pub struct ResponseFuture<F>
+    where
+        F: Future,
+        F::Output: InnerResponse,
+    {
         #[pin]
         inner: F,
         accepted_codec: Option<Codec>,
-        min_size: usize,
+        // Compressed representations the request's conditional headers
+        // referred to
+        validated: Validated,
+        // Shared dictionary the client has, and the codec to compress with it
+        dictionary: Option<(Codec, Arc<Dictionary>)>,
+        request: RequestInfo,
+        // Policy set by upstream middleware for this request
+        policy: Option<CompressionPolicy>,
+        settings: Arc<Settings>,
+        buffering: Option<Buffering<<F::Output as InnerResponse>::Body>>,
+    }
+}
+
+mod private {
+    /// The output of an inner service's future, naming its response body
+    /// type so `ResponseFuture` is generic over the future alone.
+    pub trait InnerResponse {
+        type Body;
+    }
+
+    impl<B, E> InnerResponse for Result<http::Response<B>, E> {
+        type Body = B;
     }
 }
 
-impl<F> ResponseFuture<F> {
-    pub(crate) fn new(inner: F, accepted_codec: Option<Codec>, min_size: usize) -> Self {
+/// A response whose body is being read up to the minimum size, before
+/// deciding whether to compress it.
+struct Buffering<B> {
+    parts: response::Parts,
+    codec: Codec,
+    // Codec regular negotiation chose, if the dictionary can't be used
+    fallback: Option<Codec>,
+    dictionary: Option<Arc<Dictionary>>,
+    min_size: usize,
+    body: Pin<Box<B>>,
+    buffer: BytesMut,
+    recorder: Option<Recorder>,
+}
+
+impl<F, B, E> ResponseFuture<F>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+{
+    pub(crate) fn new(
+        inner: F,
+        accepted_codec: Option<Codec>,
+        validated: Validated,
+        dictionary: Option<(Codec, Arc<Dictionary>)>,
+        request: RequestInfo,
+        policy: Option<CompressionPolicy>,
+        settings: Arc<Settings>,
+    ) -> Self {
         Self {
             inner,
             accepted_codec,
-            min_size,
+            validated,
+            dictionary,
+            request,
+            policy,
+            settings,
+            buffering: None,
         }
     }
 }
@@ -29,89 +101,505 @@ impl<F> ResponseFuture<F> {
 impl<F, B, E> Future for ResponseFuture<F>
 where
     F: Future<Output = Result<Response<B>, E>>,
+    B: Body,
+    B::Data: Buf,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
 {
     type Output = Result<Response<CompressionBody<B>>, E>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let this = self.project();
+        let mut this = self.project();
 
-        match this.inner.poll(cx) {
-            Poll::Pending => Poll::Pending,
-            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
-            Poll::Ready(Ok(response)) => {
-                let response = wrap_response(response, *this.accepted_codec, *this.min_size);
-                Poll::Ready(Ok(response))
+        loop {
+            if let Some(buffering) = this.buffering.as_mut() {
+                let filled = ready!(buffering.poll_fill(cx));
+                let buffering = this.buffering.take().expect("buffering state");
+                return Poll::Ready(Ok(buffering.into_response(filled, this.settings)));
+            }
+
+            let response = ready!(this.inner.as_mut().poll(cx))?;
+            let (mut parts, body) = response.into_parts();
+            let settings = &**this.settings;
+
+            // A 304 validating a compressed representation describes it
+            if parts.status == StatusCode::NOT_MODIFIED {
+                settings
+                    .etag
+                    .rewrite_not_modified(&mut parts.headers, this.validated);
+            }
+            add_use_as_dictionary(&mut parts, this.request, settings);
+
+            // A shared dictionary the client has beats regular compression
+            let negotiated = match this.dictionary {
+                Some((codec, _)) => Some(*codec),
+                None => *this.accepted_codec,
+            };
+            let policy = this.policy.as_ref();
+            let codec = select_codec(&parts, &body, negotiated, policy, this.request, settings);
+            #[cfg(feature = "tracing")]
+            if let Err(reason) = codec {
+                tracing::debug!(reason, status = %parts.status, "response not compressed");
+            }
+            let codec = codec.ok();
+            // The dictionary was negotiated for its codec only
+            let dictionary = this
+                .dictionary
+                .take()
+                .filter(|(dictionary_codec, _)| Some(*dictionary_codec) == codec)
+                .map(|(_, dictionary)| dictionary);
+
+            let mut recorder = None;
+            if let Some(codec) = codec
+                && dictionary.is_none()
+                && let Some((cache, key)) = cache_key(&parts, &body, this.request, settings)
+            {
+                match cache.get(&key, codec) {
+                    Some(data) => {
+                        return Poll::Ready(Ok(cached_response(parts, codec, data, settings)));
+                    }
+                    None => recorder = Some(cache.recorder(key, codec)),
+                }
+            }
+
+            let min_size = min_size(policy, settings);
+            match codec {
+                Some(codec) if should_buffer(&parts, &body, min_size, this.request, settings) => {
+                    *this.buffering = Some(Buffering {
+                        parts,
+                        codec,
+                        fallback: *this.accepted_codec,
+                        dictionary,
+                        min_size: min_size.get(),
+                        body: Box::pin(body),
+                        buffer: BytesMut::new(),
+                        recorder,
+                    });
+                }
+                codec => {
+                    let mut response = wrap_response(
+                        parts,
+                        body,
+                        codec,
+                        *this.accepted_codec,
+                        dictionary.as_deref(),
+                        this.request,
+                        settings,
+                    );
+                    if let Some(recorder) = recorder {
+                        response.body_mut().record(recorder);
+                    }
+                    return Poll::Ready(Ok(response));
+                }
             }
         }
     }
 }
 
-/// Wraps the response body with compression if appropriate.
-fn wrap_response<B>(
-    response: Response<B>,
+/// How reading the beginning of a body ended.
+enum Filled {
+    /// The minimum size was reached.
+    MinSize,
+    /// The body ended first, with the given trailers or error.
+    Ended(Option<header::HeaderMap>, Option<io::Error>),
+}
+
+impl<B> Buffering<B>
+where
+    B: Body,
+    B::Data: Buf,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    /// Reads the body until the minimum size is reached or it ends.
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<Filled> {
+        while self.buffer.len() < self.min_size {
+            match ready!(self.body.as_mut().poll_frame(cx)) {
+                None => return Poll::Ready(Filled::Ended(None, None)),
+                Some(Err(e)) => {
+                    return Poll::Ready(Filled::Ended(None, Some(io::Error::other(e.into()))));
+                }
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) => self.buffer.put(data),
+                    Err(frame) => {
+                        if let Ok(trailers) = frame.into_trailers() {
+                            return Poll::Ready(Filled::Ended(Some(trailers), None));
+                        }
+                    }
+                },
+            }
+        }
+        Poll::Ready(Filled::MinSize)
+    }
+
+    /// Builds the response, compressed only if the minimum size was reached.
+    fn into_response(self, filled: Filled, settings: &Settings) -> Response<CompressionBody<B>> {
+        let Self {
+            mut parts,
+            codec,
+            fallback,
+            dictionary,
+            body,
+            buffer,
+            recorder,
+            ..
+        } = self;
+        let prefix = buffer.freeze();
+
+        let body = match filled {
+            Filled::MinSize => {
+                match new_encoder(&parts, codec, fallback, dictionary.as_deref(), settings) {
+                    Some((codec, encoder, coding)) => {
+                        let always_flush = is_always_flush(&parts);
+                        let padding =
+                            set_compression_headers(&mut parts.headers, codec, coding, settings);
+                        let mut body = CompressionBody::buffered(
+                            Replay::new(prefix, body),
+                            Some((encoder, always_flush)),
+                        );
+                        if let Some(padding) = padding {
+                            body.pad(padding);
+                        }
+                        if let Some(observation) =
+                            Observation::new(codec, settings.observer.clone())
+                        {
+                            body.observe(observation);
+                        }
+                        body
+                    }
+                    None => CompressionBody::buffered(Replay::new(prefix, body), None),
+                }
+            }
+            Filled::Ended(trailers, error) => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(
+                    reason = "body below minimum size",
+                    status = %parts.status,
+                    "response not compressed"
+                );
+                CompressionBody::buffered(Replay::ended(prefix, trailers, error), None)
+            }
+        };
+        parts.extensions.remove::<CompressionOverride>();
+        let mut response = Response::from_parts(parts, body);
+        if let Some(recorder) = recorder {
+            response.body_mut().record(recorder);
+        }
+        response
+    }
+}
+
+/// Decides which codec, if any, the response is compressed with.
+///
+/// Returns the rule that left the response uncompressed otherwise.
+fn select_codec<B: Body>(
+    parts: &response::Parts,
+    body: &B,
     accepted_codec: Option<Codec>,
-    min_size: usize,
-) -> Response<CompressionBody<B>> {
-    let (mut parts, body) = response.into_parts();
-
-    // Determine if we should compress
-    let dominated_codec = accepted_codec.filter(|_| {
-        !has_content_encoding(&parts.headers)
-            && !has_content_range(&parts.headers)
-            && !is_uncompressible_content_type(&parts.headers)
-            && !is_below_min_size(&parts.headers, min_size)
-    });
-
-    let body = if let Some(codec) = dominated_codec {
-        // Check for x-accel-buffering: no header or streaming content types
-        let always_flush = parts
-            .headers
-            .get("x-accel-buffering")
-            .and_then(|v| v.to_str().ok())
-            .is_some_and(|v| v.eq_ignore_ascii_case("no"))
-            || is_streaming_content_type(&parts.headers);
-
-        // Add Content-Encoding header
-        parts.headers.insert(
-            header::CONTENT_ENCODING,
-            header::HeaderValue::from_static(codec.content_encoding()),
-        );
+    policy: Option<&CompressionPolicy>,
+    request: &RequestInfo,
+    settings: &Settings,
+) -> Result<Codec, &'static str> {
+    // Responses that never have a body are left untouched
+    if is_bodiless_status(parts.status) {
+        return Err("status without body");
+    }
+
+    let info = ResponseInfo::new(parts, body.size_hint());
+    let breach_allows = || {
+        settings
+            .breach
+            .as_ref()
+            .is_none_or(|breach| breach.should_compress(request, &info))
+    };
+    match parts.extensions.get::<CompressionOverride>() {
+        Some(CompressionOverride::Disable) => return Err("disabled by override"),
+        Some(CompressionOverride::ForceCodec(codec)) => {
+            // The codec must still be enabled, and the response not excluded
+            // for safety
+            return if !settings.preference.is_enabled(*codec)
+                || policy.is_some_and(|policy| !policy.allows(*codec))
+            {
+                Err("forced codec disabled")
+            } else if !NoContentEncoding.should_compress(request, &info) {
+                Err("content-encoding present")
+            } else if !NoContentRange.should_compress(request, &info) {
+                Err("content-range present")
+            } else if parts.extensions.get::<NoCompression>().is_some() {
+                Err("no compression extension")
+            } else if !breach_allows() {
+                Err("cross-site request")
+            } else if !AcceptEncoding::from_headers(request.headers()).accepts_codec(*codec) {
+                Err("forced codec not accepted")
+            } else {
+                Ok(*codec)
+            };
+        }
+        _ => {}
+    }
+
+    let codec = accepted_codec.ok_or("no accepted content coding")?;
+    settings
+        .rules
+        .check_with(request, &info, min_size(policy, settings))?;
+    if !breach_allows() {
+        return Err("cross-site request");
+    }
+    if settings
+        .predicate
+        .as_ref()
+        .is_some_and(|predicate| !predicate.should_compress(request, &info))
+    {
+        return Err("custom predicate");
+    }
+    Ok(codec)
+}
 
-        // Remove Content-Length since compressed size is unknown
-        parts.headers.remove(header::CONTENT_LENGTH);
+/// Returns the minimum body size, which the request's policy may override.
+fn min_size(policy: Option<&CompressionPolicy>, settings: &Settings) -> MinSize {
+    let min_size = settings.rules.min_size;
+    policy.map_or(min_size, |policy| policy.min_size_or(min_size))
+}
 
-        // Remove Accept-Ranges since we can't support ranges on compressed content
-        parts.headers.remove(header::ACCEPT_RANGES);
+/// Checks if the body must be read up to the minimum size before deciding
+/// whether to compress it.
+fn should_buffer<B: Body>(
+    parts: &response::Parts,
+    body: &B,
+    min_size: MinSize,
+    request: &RequestInfo,
+    settings: &Settings,
+) -> bool {
+    settings.buffer_unknown_size
+        && min_size.get() > 0
+        && request.method() != Method::HEAD
+        && !is_always_flush(parts)
+        && ResponseInfo::new(parts, body.size_hint())
+            .body_len_upper_bound()
+            .is_none()
+}
+
+/// Returns the cache and key under which the compressed body of a response
+/// is cached, if it can be.
+fn cache_key<'a, B: Body>(
+    parts: &response::Parts,
+    body: &B,
+    request: &RequestInfo,
+    settings: &'a Settings,
+) -> Option<(&'a CompressionCache, String)> {
+    let cache = settings.cache.as_ref()?;
+    if request.method() == Method::HEAD
+        || is_always_flush(parts)
+        || settings
+            .breach
+            .as_ref()
+            .is_some_and(|breach| breach.pads_body(&parts.headers))
+    {
+        return None;
+    }
+    let key = cache.key(request, &ResponseInfo::new(parts, body.size_hint()))?;
+    Some((cache, key))
+}
 
-        // Add Accept-Encoding to Vary header if not present
-        add_vary_accept_encoding(&mut parts.headers);
+/// Builds a compressed response from a cached body, dropping the original one.
+fn cached_response<B>(
+    mut parts: response::Parts,
+    codec: Codec,
+    data: Bytes,
+    settings: &Settings,
+) -> Response<CompressionBody<B>> {
+    set_compression_headers(
+        &mut parts.headers,
+        codec,
+        codec.content_encoding(),
+        settings,
+    );
+    parts.headers.insert(
+        header::CONTENT_LENGTH,
+        header::HeaderValue::from(data.len()),
+    );
+    parts.extensions.remove::<CompressionOverride>();
+    Response::from_parts(parts, CompressionBody::cached(data))
+}
 
-        CompressionBody::compressed(body, codec, always_flush)
+/// Wraps the response body with the selected codec, if any.
+fn wrap_response<B>(
+    mut parts: response::Parts,
+    body: B,
+    codec: Option<Codec>,
+    fallback: Option<Codec>,
+    dictionary: Option<&Dictionary>,
+    request: &RequestInfo,
+    settings: &Settings,
+) -> Response<CompressionBody<B>> {
+    let body = if let Some(codec) = codec {
+        if request.method() == Method::HEAD {
+            // Advertise the encoding a GET would get, but there is no body to encode
+            let coding = dictionary
+                .and(codec.dictionary_encoding())
+                .unwrap_or(codec.content_encoding());
+            set_compression_headers(&mut parts.headers, codec, coding, settings);
+            CompressionBody::passthrough(body)
+        } else if let Some((codec, encoder, coding)) =
+            new_encoder(&parts, codec, fallback, dictionary, settings)
+        {
+            let always_flush = is_always_flush(&parts);
+            let padding = set_compression_headers(&mut parts.headers, codec, coding, settings);
+            let mut body = CompressionBody::encoded(body, encoder, always_flush);
+            if let Some(padding) = padding {
+                body.pad(padding);
+            }
+            if let Some(observation) = Observation::new(codec, settings.observer.clone()) {
+                body.observe(observation);
+            }
+            body
+        } else {
+            CompressionBody::passthrough(body)
+        }
     } else {
         CompressionBody::passthrough(body)
     };
 
+    parts.extensions.remove::<CompressionOverride>();
     Response::from_parts(parts, body)
 }
 
-/// Checks if Content-Encoding header is already present.
-fn has_content_encoding(headers: &header::HeaderMap) -> bool {
-    headers.contains_key(header::CONTENT_ENCODING)
+/// Creates the encoder for a response, along with the codec and content
+/// coding it produces.
+///
+/// If the dictionary can't be used, falls back to `fallback`, the codec
+/// regular negotiation chose, as the client may not accept the dictionary's
+/// codec on its own. Returns `None` if there is no such codec.
+fn new_encoder(
+    parts: &response::Parts,
+    codec: Codec,
+    fallback: Option<Codec>,
+    dictionary: Option<&Dictionary>,
+    settings: &Settings,
+) -> Option<(Codec, Box<dyn EncodeV2 + Send>, &'static str)> {
+    let codec = match dictionary {
+        Some(dictionary) => {
+            let level = compression_level(parts, codec, settings);
+            if let Some(coding) = codec.dictionary_encoding()
+                && let Ok(encoder) = codec.encoder_with_dictionary(level, dictionary)
+            {
+                return Some((codec, encoder, coding));
+            }
+            fallback?
+        }
+        None => codec,
+    };
+    let level = compression_level(parts, codec, settings);
+    Some((codec, codec.encoder(level), codec.content_encoding()))
+}
+
+/// Adds a `Use-As-Dictionary` header to successful responses for the path of
+/// a shared dictionary.
+fn add_use_as_dictionary(parts: &mut response::Parts, request: &RequestInfo, settings: &Settings) {
+    if !parts.status.is_success() {
+        return;
+    }
+    let path = request.uri().path();
+    if let Some(value) = settings
+        .dictionaries
+        .iter()
+        .find(|dictionary| dictionary.path() == path)
+        .and_then(|dictionary| dictionary.use_as_dictionary())
+    {
+        parts.headers.insert(USE_AS_DICTIONARY, value);
+    }
+}
+
+/// Updates the headers of a response that is being compressed with the
+/// codec, producing the given content coding.
+///
+/// Returns the padding to append to the body, if any.
+fn set_compression_headers(
+    headers: &mut header::HeaderMap,
+    codec: Codec,
+    coding: &'static str,
+    settings: &Settings,
+) -> Option<Bytes> {
+    // Add Content-Encoding header
+    headers.insert(
+        header::CONTENT_ENCODING,
+        header::HeaderValue::from_static(coding),
+    );
+
+    // Remove Content-Length since compressed size is unknown
+    headers.remove(header::CONTENT_LENGTH);
+
+    // Remove Accept-Ranges since we can't support ranges on compressed content
+    headers.remove(header::ACCEPT_RANGES);
+
+    // The compressed representation needs its own entity tag
+    settings.etag.rewrite_response(headers, coding);
+
+    // Add Accept-Encoding to Vary header if not present
+    add_vary_accept_encoding(headers);
+
+    // Dictionary-compressed responses also depend on the client's dictionary
+    if coding != codec.content_encoding() {
+        add_vary(headers, "available-dictionary");
+    }
+
+    settings
+        .breach
+        .as_ref()
+        .and_then(|breach| breach.apply(headers))
+}
+
+/// Returns the level the response is compressed with, which may be
+/// overridden by the response.
+fn compression_level(
+    parts: &response::Parts,
+    codec: Codec,
+    settings: &Settings,
+) -> CompressionLevel {
+    parts
+        .extensions
+        .get::<CompressionOverride>()
+        .and_then(CompressionOverride::level)
+        .unwrap_or_else(|| settings.levels.get(codec))
 }
 
-/// Checks if Content-Range header is present (range response).
-fn has_content_range(headers: &header::HeaderMap) -> bool {
-    headers.contains_key(header::CONTENT_RANGE)
+/// Checks for an always flush override, an `x-accel-buffering: no` header or
+/// a streaming content type.
+fn is_always_flush(parts: &response::Parts) -> bool {
+    if let Some(always_flush) = parts
+        .extensions
+        .get::<CompressionOverride>()
+        .and_then(CompressionOverride::always_flush)
+    {
+        return always_flush;
+    }
+
+    let headers = &parts.headers;
+    headers
+        .get("x-accel-buffering")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("no"))
+        || is_streaming_content_type(headers)
+}
+
+/// Checks if the status never allows a response body (1xx, 204 and 304).
+fn is_bodiless_status(status: StatusCode) -> bool {
+    status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
 }
 
 /// Adds Accept-Encoding to the Vary header if not already present.
-fn add_vary_accept_encoding(headers: &mut header::HeaderMap) {
-    // Check all Vary headers to see if Accept-Encoding is already present
+pub(crate) fn add_vary_accept_encoding(headers: &mut header::HeaderMap) {
+    add_vary(headers, "accept-encoding");
+}
+
+/// Adds a request header name to the Vary header if not already present.
+pub(crate) fn add_vary(headers: &mut header::HeaderMap, name: &'static str) {
+    // Check all Vary headers to see if the name is already present
     for vary in headers.get_all(header::VARY) {
         if let Ok(vary_str) = vary.to_str() {
             let dominated = vary_str.split(',').any(|v| {
                 let v = v.trim();
-                v.eq_ignore_ascii_case("*") || v.eq_ignore_ascii_case("accept-encoding")
+                v.eq_ignore_ascii_case("*") || v.eq_ignore_ascii_case(name)
             });
             if dominated {
                 return;
@@ -119,28 +607,8 @@ fn add_vary_accept_encoding(headers: &mut header::HeaderMap) {
         }
     }
 
-    // Append Accept-Encoding to Vary header
-    headers.append(
-        header::VARY,
-        header::HeaderValue::from_static("accept-encoding"),
-    );
-}
-
-/// Checks if the content type should not be compressed.
-fn is_uncompressible_content_type(headers: &header::HeaderMap) -> bool {
-    let Some(content_type) = headers
-        .get(header::CONTENT_TYPE)
-        .and_then(|v| v.to_str().ok())
-    else {
-        return false;
-    };
-
-    // Skip all images except SVG
-    if content_type.starts_with("image/") {
-        return !content_type.starts_with("image/svg+xml");
-    }
-
-    false
+    // Append the name to the Vary header
+    headers.append(header::VARY, header::HeaderValue::from_static(name));
 }
 
 /// Checks if the content type requires always flushing (e.g., streaming).
@@ -151,30 +619,144 @@ fn is_streaming_content_type(headers: &header::HeaderMap) -> bool {
         .is_some_and(|ct| ct.starts_with("text/event-stream") || ct.starts_with("application/grpc"))
 }
 
-/// Checks if Content-Length is below the minimum size.
-fn is_below_min_size(headers: &header::HeaderMap, min_size: usize) -> bool {
-    headers
-        .get(header::CONTENT_LENGTH)
-        .and_then(|v| v.to_str().ok())
-        .and_then(|v| v.parse::<usize>().ok())
-        .is_some_and(|len| len < min_size)
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
-    #[allow(unused_imports)]
-    use crate::body::CompressState;
+    #[cfg(feature = "gzip")]
+    use crate::body::tests::gunzip;
+    #[cfg(feature = "gzip")]
+    use crate::body::{CompressState, Kind};
 
-    fn make_response(body: &'static str) -> Response<&'static str> {
-        Response::new(body)
+    fn wrap<B: Body>(
+        response: Response<B>,
+        accepted_codec: Option<Codec>,
+        min_size: usize,
+    ) -> Response<CompressionBody<B>> {
+        wrap_with(response, accepted_codec, Settings::new(min_size))
     }
 
-    fn make_response_with_headers<I>(body: &'static str, headers: I) -> Response<&'static str>
+    fn wrap_with<B: Body>(
+        response: Response<B>,
+        accepted_codec: Option<Codec>,
+        settings: Settings,
+    ) -> Response<CompressionBody<B>> {
+        wrap_request(response, accepted_codec, &RequestInfo::default(), &settings)
+    }
+
+    #[cfg(feature = "gzip")]
+    fn wrap_head(
+        response: Response<String>,
+        accepted_codec: Option<Codec>,
+    ) -> Response<CompressionBody<String>> {
+        let request = http::Request::head("/").body(()).unwrap();
+        wrap_request(
+            response,
+            accepted_codec,
+            &RequestInfo::from_request(&request),
+            &Settings::new(0),
+        )
+    }
+
+    fn wrap_request<B: Body>(
+        response: Response<B>,
+        accepted_codec: Option<Codec>,
+        request: &RequestInfo,
+        settings: &Settings,
+    ) -> Response<CompressionBody<B>> {
+        let (parts, body) = response.into_parts();
+        let codec = select_codec(&parts, &body, accepted_codec, None, request, settings).ok();
+        wrap_response(parts, body, codec, accepted_codec, None, request, settings)
+    }
+
+    /// A body that yields its chunks without reporting its size.
+    ///
+    /// A `None` chunk makes the body return `Pending` once, after waking the
+    /// task.
+    #[cfg(feature = "gzip")]
+    struct UnknownSize(std::collections::VecDeque<Option<&'static str>>);
+
+    #[cfg(feature = "gzip")]
+    impl UnknownSize {
+        fn new(chunks: &[&'static str]) -> Self {
+            Self(chunks.iter().copied().map(Some).collect())
+        }
+
+        fn with_pending(chunks: &[Option<&'static str>]) -> Self {
+            Self(chunks.iter().copied().collect())
+        }
+    }
+
+    #[cfg(feature = "gzip")]
+    impl Body for UnknownSize {
+        type Data = bytes::Bytes;
+        type Error = std::convert::Infallible;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<http_body::Frame<Self::Data>, Self::Error>>> {
+            match self.0.pop_front() {
+                Some(Some(chunk)) => Poll::Ready(Some(Ok(http_body::Frame::data(chunk.into())))),
+                Some(None) => {
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+                None => Poll::Ready(None),
+            }
+        }
+    }
+
+    /// Polls a future to completion, or until it returns `Pending` as many
+    /// times as given.
+    fn poll_future<T>(future: impl Future<Output = T>, max_pending: usize) -> Option<T> {
+        let mut future = std::pin::pin!(future);
+        let mut cx = Context::from_waker(std::task::Waker::noop());
+        for _ in 0..=max_pending {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return Some(output);
+            }
+        }
+        None
+    }
+
+    /// Runs a response through a `ResponseFuture` with the given settings and
+    /// collects its body.
+    fn respond<B>(
+        response: Response<B>,
+        accepted_codec: Option<Codec>,
+        settings: Settings,
+    ) -> (http::response::Parts, bytes::Bytes)
+    where
+        B: Body,
+        B::Data: Buf,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        use http_body_util::BodyExt;
+
+        let future = ResponseFuture::new(
+            std::future::ready(Ok::<_, std::convert::Infallible>(response)),
+            accepted_codec,
+            Validated::new(),
+            None,
+            RequestInfo::default(),
+            None,
+            Arc::new(settings),
+        );
+        let response = poll_future(future, 10).unwrap().unwrap();
+        let (parts, body) = response.into_parts();
+        let body = poll_future(body.collect(), 10).unwrap().unwrap();
+        (parts, body.to_bytes())
+    }
+
+    fn make_response(body: &'static str) -> Response<String> {
+        Response::new(body.to_owned())
+    }
+
+    fn make_response_with_headers<I>(body: &'static str, headers: I) -> Response<String>
     where
         I: IntoIterator<Item = (&'static str, &'static str)>,
     {
-        let mut response = Response::new(body);
+        let mut response = Response::new(body.to_owned());
         for (name, value) in headers {
             response
                 .headers_mut()
@@ -187,11 +769,11 @@ mod tests {
     #[cfg(feature = "gzip")]
     fn test_compress_when_accept_encoding_present() {
         let response = make_response("hello world");
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 0);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
 
         // Should be compressed
-        match wrapped.body() {
-            crate::body::CompressionBody::Compressed { state, .. } => {
+        match &wrapped.body().kind {
+            crate::body::Kind::Compressed { state, .. } => {
                 assert_eq!(state.state(), CompressState::Reading);
             }
             _ => panic!("Expected compressed body"),
@@ -207,11 +789,11 @@ mod tests {
     #[test]
     fn test_no_compress_when_no_accept_encoding() {
         let response = make_response("hello world");
-        let wrapped = wrap_response(response, None, 0);
+        let wrapped = wrap(response, None, 0);
 
         // Should be passthrough
-        match wrapped.body() {
-            crate::body::CompressionBody::Passthrough { .. } => {}
+        match &wrapped.body().kind {
+            crate::body::Kind::Passthrough { .. } => {}
             _ => panic!("Expected passthrough body"),
         }
 
@@ -224,11 +806,11 @@ mod tests {
     fn test_no_compress_when_content_encoding_present() {
         let response =
             make_response_with_headers("hello world", [("content-encoding", "identity")]);
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 0);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
 
         // Should be passthrough
-        match wrapped.body() {
-            crate::body::CompressionBody::Passthrough { .. } => {}
+        match &wrapped.body().kind {
+            crate::body::Kind::Passthrough { .. } => {}
             _ => panic!("Expected passthrough body"),
         }
     }
@@ -237,11 +819,11 @@ mod tests {
     #[cfg(feature = "gzip")]
     fn test_no_compress_image_png() {
         let response = make_response_with_headers("PNG data", [("content-type", "image/png")]);
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 0);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
 
         // Should be passthrough
-        match wrapped.body() {
-            crate::body::CompressionBody::Passthrough { .. } => {}
+        match &wrapped.body().kind {
+            crate::body::Kind::Passthrough { .. } => {}
             _ => panic!("Expected passthrough body for image/png"),
         }
     }
@@ -250,11 +832,11 @@ mod tests {
     #[cfg(feature = "gzip")]
     fn test_no_compress_image_jpeg() {
         let response = make_response_with_headers("JPEG data", [("content-type", "image/jpeg")]);
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 0);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
 
         // Should be passthrough
-        match wrapped.body() {
-            crate::body::CompressionBody::Passthrough { .. } => {}
+        match &wrapped.body().kind {
+            crate::body::Kind::Passthrough { .. } => {}
             _ => panic!("Expected passthrough body for image/jpeg"),
         }
     }
@@ -263,11 +845,11 @@ mod tests {
     #[cfg(feature = "gzip")]
     fn test_no_compress_image_gif() {
         let response = make_response_with_headers("GIF data", [("content-type", "image/gif")]);
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 0);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
 
         // Should be passthrough
-        match wrapped.body() {
-            crate::body::CompressionBody::Passthrough { .. } => {}
+        match &wrapped.body().kind {
+            crate::body::Kind::Passthrough { .. } => {}
             _ => panic!("Expected passthrough body for image/gif"),
         }
     }
@@ -276,25 +858,64 @@ mod tests {
     #[cfg(feature = "gzip")]
     fn test_no_compress_image_webp() {
         let response = make_response_with_headers("WebP data", [("content-type", "image/webp")]);
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 0);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
 
         // Should be passthrough
-        match wrapped.body() {
-            crate::body::CompressionBody::Passthrough { .. } => {}
+        match &wrapped.body().kind {
+            crate::body::Kind::Passthrough { .. } => {}
             _ => panic!("Expected passthrough body for image/webp"),
         }
     }
 
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_no_compress_already_compressed_types() {
+        for content_type in [
+            "video/mp4",
+            "audio/mpeg",
+            "font/woff2",
+            "application/zip",
+            "application/gzip",
+            "application/octet-stream",
+            "IMAGE/PNG; foo=bar",
+        ] {
+            let response = make_response_with_headers("data", [("content-type", content_type)]);
+            let wrapped = wrap(response, Some(Codec::Gzip), 0);
+
+            match &wrapped.body().kind {
+                crate::body::Kind::Passthrough { .. } => {}
+                _ => panic!("Expected passthrough body for {content_type}"),
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_compress_allowed_content_type() {
+        let mut settings = Settings::new(0);
+        settings.rules.content_types =
+            crate::ContentTypeFilter::default().allow("application/octet-stream");
+
+        let response =
+            make_response_with_headers("data", [("content-type", "application/octet-stream")]);
+        let wrapped = wrap_with(response, Some(Codec::Gzip), settings);
+
+        match &wrapped.body().kind {
+            crate::body::Kind::Compressed { .. } => {}
+            _ => panic!("Expected compressed body for allowed content type"),
+        }
+    }
+
     #[test]
     #[cfg(feature = "gzip")]
     fn test_compress_image_svg() {
         let response =
             make_response_with_headers("<svg></svg>", [("content-type", "image/svg+xml")]);
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 0);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
 
         // Should be compressed (SVG is text-based)
-        match wrapped.body() {
-            crate::body::CompressionBody::Compressed { .. } => {}
+        match &wrapped.body().kind {
+            crate::body::Kind::Compressed { .. } => {}
             _ => panic!("Expected compressed body for image/svg+xml"),
         }
     }
@@ -306,11 +927,11 @@ mod tests {
             "<svg></svg>",
             [("content-type", "image/svg+xml; charset=utf-8")],
         );
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 0);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
 
         // Should be compressed
-        match wrapped.body() {
-            crate::body::CompressionBody::Compressed { .. } => {}
+        match &wrapped.body().kind {
+            crate::body::Kind::Compressed { .. } => {}
             _ => panic!("Expected compressed body for image/svg+xml with charset"),
         }
     }
@@ -319,11 +940,11 @@ mod tests {
     #[cfg(feature = "gzip")]
     fn test_compress_text_html() {
         let response = make_response_with_headers("<html></html>", [("content-type", "text/html")]);
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 0);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
 
         // Should be compressed
-        match wrapped.body() {
-            crate::body::CompressionBody::Compressed { .. } => {}
+        match &wrapped.body().kind {
+            crate::body::Kind::Compressed { .. } => {}
             _ => panic!("Expected compressed body for text/html"),
         }
     }
@@ -332,11 +953,11 @@ mod tests {
     #[cfg(feature = "gzip")]
     fn test_no_compress_below_min_size() {
         let response = make_response_with_headers("small", [("content-length", "5")]);
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 100);
+        let wrapped = wrap(response, Some(Codec::Gzip), 100);
 
         // Should be passthrough (5 < 100)
-        match wrapped.body() {
-            crate::body::CompressionBody::Passthrough { .. } => {}
+        match &wrapped.body().kind {
+            crate::body::Kind::Passthrough { .. } => {}
             _ => panic!("Expected passthrough body below min size"),
         }
     }
@@ -346,11 +967,11 @@ mod tests {
     fn test_compress_above_min_size() {
         let response =
             make_response_with_headers("large enough content", [("content-length", "200")]);
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 100);
+        let wrapped = wrap(response, Some(Codec::Gzip), 100);
 
         // Should be compressed (200 >= 100)
-        match wrapped.body() {
-            crate::body::CompressionBody::Compressed { .. } => {}
+        match &wrapped.body().kind {
+            crate::body::Kind::Compressed { .. } => {}
             _ => panic!("Expected compressed body above min size"),
         }
 
@@ -361,25 +982,167 @@ mod tests {
     #[test]
     #[cfg(feature = "gzip")]
     fn test_compress_unknown_size() {
-        // No Content-Length header means unknown size, should compress
-        let response = make_response("unknown size content");
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 100);
+        // No Content-Length header and no size hint means unknown size, should compress
+        let response = Response::new(UnknownSize::new(&["unknown size content"]));
+        let wrapped = wrap(response, Some(Codec::Gzip), 100);
 
         // Should be compressed (unknown size doesn't trigger min_size check)
-        match wrapped.body() {
-            crate::body::CompressionBody::Compressed { .. } => {}
+        match &wrapped.body().kind {
+            crate::body::Kind::Compressed { .. } => {}
             _ => panic!("Expected compressed body for unknown size"),
         }
     }
 
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_buffer_unknown_size_small_body() {
+        let mut settings = Settings::new(100);
+        settings.buffer_unknown_size = true;
+        let response = Response::new(UnknownSize::with_pending(&[
+            Some("small "),
+            None,
+            Some("body"),
+        ]));
+
+        let (parts, body) = respond(response, Some(Codec::Gzip), settings);
+        assert!(parts.headers.get(header::CONTENT_ENCODING).is_none());
+        assert!(parts.headers.get(header::VARY).is_none());
+        assert_eq!(body, "small body");
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_buffer_unknown_size_large_body() {
+        let mut settings = Settings::new(10);
+        settings.buffer_unknown_size = true;
+        let response = Response::new(UnknownSize::with_pending(&[
+            Some("hello "),
+            None,
+            Some("wonderful "),
+            Some("world"),
+        ]));
+
+        let (parts, body) = respond(response, Some(Codec::Gzip), settings);
+        assert_eq!(parts.headers.get(header::CONTENT_ENCODING).unwrap(), "gzip");
+        assert_eq!(gunzip(&body), b"hello wonderful world");
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_buffer_unknown_size_waits_for_decision() {
+        let mut settings = Settings::new(10);
+        settings.buffer_unknown_size = true;
+        let response = Response::new(UnknownSize::with_pending(&[
+            Some("hello"),
+            None,
+            None,
+            Some("world"),
+        ]));
+        let future = ResponseFuture::new(
+            std::future::ready(Ok::<_, std::convert::Infallible>(response)),
+            Some(Codec::Gzip),
+            Validated::new(),
+            None,
+            RequestInfo::default(),
+            None,
+            Arc::new(settings),
+        );
+        let mut future = std::pin::pin!(future);
+        let mut cx = Context::from_waker(std::task::Waker::noop());
+
+        assert!(future.as_mut().poll(&mut cx).is_pending());
+        assert!(future.as_mut().poll(&mut cx).is_pending());
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(Ok(response)) => {
+                assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+            }
+            _ => panic!("Expected response once the minimum size is reached"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_buffer_unknown_size_disabled_by_default() {
+        let response = Response::new(UnknownSize::new(&["small"]));
+
+        let (parts, body) = respond(response, Some(Codec::Gzip), Settings::new(100));
+        assert_eq!(parts.headers.get(header::CONTENT_ENCODING).unwrap(), "gzip");
+        assert_eq!(gunzip(&body), b"small");
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_buffer_unknown_size_skips_streaming() {
+        let mut settings = Settings::new(100);
+        settings.buffer_unknown_size = true;
+        let mut response = Response::new(UnknownSize::with_pending(&[None, Some("data: hi\n\n")]));
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static("text/event-stream"),
+        );
+
+        // Resolves without polling the body
+        let future = ResponseFuture::new(
+            std::future::ready(Ok::<_, std::convert::Infallible>(response)),
+            Some(Codec::Gzip),
+            Validated::new(),
+            None,
+            RequestInfo::default(),
+            None,
+            Arc::new(settings),
+        );
+        let response = poll_future(future, 0).unwrap().unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_no_compress_small_full_body() {
+        // Full reports an exact size hint even without a Content-Length header
+        let response = Response::new(http_body_util::Full::new(bytes::Bytes::from("small")));
+        let wrapped = wrap(response, Some(Codec::Gzip), 100);
+
+        match &wrapped.body().kind {
+            crate::body::Kind::Passthrough { .. } => {}
+            _ => panic!("Expected passthrough body for small Full body"),
+        }
+        assert!(wrapped.headers().get(header::CONTENT_ENCODING).is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_compress_large_full_body() {
+        let response = Response::new(http_body_util::Full::new(bytes::Bytes::from(
+            "a".repeat(200),
+        )));
+        let wrapped = wrap(response, Some(Codec::Gzip), 100);
+
+        match &wrapped.body().kind {
+            crate::body::Kind::Compressed { .. } => {}
+            _ => panic!("Expected compressed body for large Full body"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_no_compress_empty_body() {
+        let response = Response::new(http_body_util::Empty::<bytes::Bytes>::new());
+        let wrapped = wrap(response, Some(Codec::Gzip), 1);
+
+        match &wrapped.body().kind {
+            crate::body::Kind::Passthrough { .. } => {}
+            _ => panic!("Expected passthrough body for empty body"),
+        }
+    }
+
     #[test]
     #[cfg(feature = "gzip")]
     fn test_always_flush_when_x_accel_buffering_no() {
         let response = make_response_with_headers("streaming data", [("x-accel-buffering", "no")]);
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 0);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
 
-        match wrapped.body() {
-            crate::body::CompressionBody::Compressed { state, .. } => {
+        match &wrapped.body().kind {
+            crate::body::Kind::Compressed { state, .. } => {
                 assert!(state.always_flush());
             }
             _ => panic!("Expected compressed body"),
@@ -390,10 +1153,10 @@ mod tests {
     #[cfg(feature = "gzip")]
     fn test_no_always_flush_by_default() {
         let response = make_response("normal data");
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 0);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
 
-        match wrapped.body() {
-            crate::body::CompressionBody::Compressed { state, .. } => {
+        match &wrapped.body().kind {
+            crate::body::Kind::Compressed { state, .. } => {
                 assert!(!state.always_flush());
             }
             _ => panic!("Expected compressed body"),
@@ -404,10 +1167,10 @@ mod tests {
     #[cfg(feature = "gzip")]
     fn test_x_accel_buffering_case_insensitive() {
         let response = make_response_with_headers("streaming data", [("x-accel-buffering", "NO")]);
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 0);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
 
-        match wrapped.body() {
-            crate::body::CompressionBody::Compressed { state, .. } => {
+        match &wrapped.body().kind {
+            crate::body::Kind::Compressed { state, .. } => {
                 assert!(state.always_flush());
             }
             _ => panic!("Expected compressed body"),
@@ -418,7 +1181,7 @@ mod tests {
     #[cfg(feature = "brotli")]
     fn test_brotli_content_encoding() {
         let response = make_response("hello world");
-        let wrapped = wrap_response(response, Some(Codec::Brotli), 0);
+        let wrapped = wrap(response, Some(Codec::Brotli), 0);
 
         assert_eq!(
             wrapped.headers().get(header::CONTENT_ENCODING).unwrap(),
@@ -430,7 +1193,7 @@ mod tests {
     #[cfg(feature = "zstd")]
     fn test_zstd_content_encoding() {
         let response = make_response("hello world");
-        let wrapped = wrap_response(response, Some(Codec::Zstd), 0);
+        let wrapped = wrap(response, Some(Codec::Zstd), 0);
 
         assert_eq!(
             wrapped.headers().get(header::CONTENT_ENCODING).unwrap(),
@@ -443,11 +1206,11 @@ mod tests {
     fn test_compress_application_grpc() {
         let response =
             make_response_with_headers("grpc data", [("content-type", "application/grpc")]);
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 0);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
 
         // Should be compressed with streaming (always_flush)
-        match wrapped.body() {
-            crate::body::CompressionBody::Compressed { state, .. } => {
+        match &wrapped.body().kind {
+            crate::body::Kind::Compressed { state, .. } => {
                 assert!(state.always_flush());
             }
             _ => panic!("Expected compressed body for application/grpc"),
@@ -459,11 +1222,11 @@ mod tests {
     fn test_compress_application_grpc_with_suffix() {
         let response =
             make_response_with_headers("grpc data", [("content-type", "application/grpc+proto")]);
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 0);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
 
         // Should be compressed with streaming (always_flush)
-        match wrapped.body() {
-            crate::body::CompressionBody::Compressed { state, .. } => {
+        match &wrapped.body().kind {
+            crate::body::Kind::Compressed { state, .. } => {
                 assert!(state.always_flush());
             }
             _ => panic!("Expected compressed body for application/grpc+proto"),
@@ -475,10 +1238,10 @@ mod tests {
     fn test_compress_application_grpc_web() {
         let response =
             make_response_with_headers("grpc-web data", [("content-type", "application/grpc-web")]);
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 0);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
 
-        match wrapped.body() {
-            crate::body::CompressionBody::Compressed { state, .. } => {
+        match &wrapped.body().kind {
+            crate::body::Kind::Compressed { state, .. } => {
                 assert!(state.always_flush());
             }
             _ => panic!("Expected compressed body"),
@@ -492,10 +1255,10 @@ mod tests {
             "grpc-web data",
             [("content-type", "application/grpc-web+proto")],
         );
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 0);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
 
-        match wrapped.body() {
-            crate::body::CompressionBody::Compressed { state, .. } => {
+        match &wrapped.body().kind {
+            crate::body::Kind::Compressed { state, .. } => {
                 assert!(state.always_flush());
             }
             _ => panic!("Expected compressed body"),
@@ -507,10 +1270,10 @@ mod tests {
     fn test_always_flush_text_event_stream() {
         let response =
             make_response_with_headers("event: data\n\n", [("content-type", "text/event-stream")]);
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 0);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
 
-        match wrapped.body() {
-            crate::body::CompressionBody::Compressed { state, .. } => {
+        match &wrapped.body().kind {
+            crate::body::Kind::Compressed { state, .. } => {
                 assert!(state.always_flush());
             }
             _ => panic!("Expected compressed body"),
@@ -524,10 +1287,10 @@ mod tests {
             "event: data\n\n",
             [("content-type", "text/event-stream; charset=utf-8")],
         );
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 0);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
 
-        match wrapped.body() {
-            crate::body::CompressionBody::Compressed { state, .. } => {
+        match &wrapped.body().kind {
+            crate::body::Kind::Compressed { state, .. } => {
                 assert!(state.always_flush());
             }
             _ => panic!("Expected compressed body"),
@@ -539,20 +1302,113 @@ mod tests {
     fn test_no_compress_range_response() {
         let response =
             make_response_with_headers("partial content", [("content-range", "bytes 0-99/200")]);
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 0);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
 
         // Should be passthrough for range responses
-        match wrapped.body() {
-            crate::body::CompressionBody::Passthrough { .. } => {}
+        match &wrapped.body().kind {
+            crate::body::Kind::Passthrough { .. } => {}
             _ => panic!("Expected passthrough body for range response"),
         }
     }
 
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_no_compress_cache_control_no_transform() {
+        let response = make_response_with_headers(
+            "hello world",
+            [("cache-control", "public, no-transform, max-age=60")],
+        );
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
+
+        // Should be passthrough and untouched
+        match &wrapped.body().kind {
+            crate::body::Kind::Passthrough { .. } => {}
+            _ => panic!("Expected passthrough body for no-transform"),
+        }
+        assert!(wrapped.headers().get(header::CONTENT_ENCODING).is_none());
+        assert!(wrapped.headers().get(header::VARY).is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_compress_no_transform_when_not_respected() {
+        let mut settings = Settings::new(0);
+        settings.rules.respect_no_transform = false;
+
+        let response =
+            make_response_with_headers("hello world", [("cache-control", "no-transform")]);
+        let wrapped = wrap_with(response, Some(Codec::Gzip), settings);
+
+        match &wrapped.body().kind {
+            crate::body::Kind::Compressed { .. } => {}
+            _ => panic!("Expected compressed body when no-transform is ignored"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_no_compress_bodiless_status() {
+        for status in [
+            StatusCode::CONTINUE,
+            StatusCode::SWITCHING_PROTOCOLS,
+            StatusCode::NO_CONTENT,
+            StatusCode::NOT_MODIFIED,
+        ] {
+            let mut response = make_response("");
+            *response.status_mut() = status;
+            let wrapped = wrap(response, Some(Codec::Gzip), 0);
+
+            match &wrapped.body().kind {
+                crate::body::Kind::Passthrough { .. } => {}
+                _ => panic!("Expected passthrough body for {status}"),
+            }
+            assert!(wrapped.headers().get(header::CONTENT_ENCODING).is_none());
+            assert!(wrapped.headers().get(header::VARY).is_none());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_head_advertises_encoding_without_compressing() {
+        let response = make_response_with_headers(
+            "",
+            [("content-length", "2000"), ("accept-ranges", "bytes")],
+        );
+        let wrapped = wrap_head(response, Some(Codec::Gzip));
+
+        // The body is passed through, since HEAD responses have none
+        match &wrapped.body().kind {
+            crate::body::Kind::Passthrough { .. } => {}
+            _ => panic!("Expected passthrough body for HEAD"),
+        }
+
+        // Headers match what a GET would get
+        assert_eq!(
+            wrapped.headers().get(header::CONTENT_ENCODING).unwrap(),
+            "gzip"
+        );
+        assert!(wrapped.headers().get(header::CONTENT_LENGTH).is_none());
+        assert!(wrapped.headers().get(header::ACCEPT_RANGES).is_none());
+        assert_eq!(
+            wrapped.headers().get(header::VARY).unwrap(),
+            "accept-encoding"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_head_follows_compression_rules() {
+        let response = make_response_with_headers("", [("content-type", "image/png")]);
+        let wrapped = wrap_head(response, Some(Codec::Gzip));
+
+        assert!(wrapped.headers().get(header::CONTENT_ENCODING).is_none());
+    }
+
     #[test]
     #[cfg(feature = "gzip")]
     fn test_vary_header_added() {
         let response = make_response("hello world");
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 0);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
 
         assert_eq!(
             wrapped.headers().get(header::VARY).unwrap(),
@@ -564,7 +1420,7 @@ mod tests {
     #[cfg(feature = "gzip")]
     fn test_vary_header_appended() {
         let response = make_response_with_headers("hello world", [("vary", "origin")]);
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 0);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
 
         // With append, there will be two Vary headers
         let vary_values: Vec<_> = wrapped
@@ -580,7 +1436,7 @@ mod tests {
     #[cfg(feature = "gzip")]
     fn test_vary_header_not_duplicated() {
         let response = make_response_with_headers("hello world", [("vary", "accept-encoding")]);
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 0);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
 
         assert_eq!(
             wrapped.headers().get(header::VARY).unwrap(),
@@ -592,7 +1448,7 @@ mod tests {
     #[cfg(feature = "gzip")]
     fn test_vary_header_star_not_modified() {
         let response = make_response_with_headers("hello world", [("vary", "*")]);
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 0);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
 
         assert_eq!(wrapped.headers().get(header::VARY).unwrap(), "*");
     }
@@ -601,16 +1457,53 @@ mod tests {
     #[cfg(feature = "gzip")]
     fn test_accept_ranges_removed() {
         let response = make_response_with_headers("hello world", [("accept-ranges", "bytes")]);
-        let wrapped = wrap_response(response, Some(Codec::Gzip), 0);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
 
         // Accept-Ranges should be removed when compressing
         assert!(wrapped.headers().get(header::ACCEPT_RANGES).is_none());
     }
 
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_no_compress_when_predicate_rejects() {
+        let mut settings = Settings::new(0);
+        settings.predicate = Some(Arc::new(|_: &RequestInfo, res: &ResponseInfo<'_>| {
+            !res.headers().contains_key("x-no-compress")
+        }));
+
+        let response = make_response_with_headers("hello world", [("x-no-compress", "1")]);
+        let wrapped = wrap_with(response, Some(Codec::Gzip), settings.clone());
+        match &wrapped.body().kind {
+            crate::body::Kind::Passthrough { .. } => {}
+            _ => panic!("Expected passthrough body when predicate rejects"),
+        }
+
+        let response = make_response("hello world");
+        let wrapped = wrap_with(response, Some(Codec::Gzip), settings);
+        match &wrapped.body().kind {
+            crate::body::Kind::Compressed { .. } => {}
+            _ => panic!("Expected compressed body when predicate allows"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_predicate_cannot_override_builtin_rules() {
+        let mut settings = Settings::new(0);
+        settings.predicate = Some(Arc::new(|_: &RequestInfo, _: &ResponseInfo<'_>| true));
+
+        let response = make_response_with_headers("PNG data", [("content-type", "image/png")]);
+        let wrapped = wrap_with(response, Some(Codec::Gzip), settings);
+        match &wrapped.body().kind {
+            crate::body::Kind::Passthrough { .. } => {}
+            _ => panic!("Expected passthrough body for image/png"),
+        }
+    }
+
     #[test]
     fn test_accept_ranges_kept_when_not_compressing() {
         let response = make_response_with_headers("hello world", [("accept-ranges", "bytes")]);
-        let wrapped = wrap_response(response, None, 0);
+        let wrapped = wrap(response, None, 0);
 
         // Accept-Ranges should be kept when not compressing
         assert_eq!(
@@ -618,4 +1511,684 @@ mod tests {
             "bytes"
         );
     }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_cache_serves_compressed_body() {
+        let cache = CompressionCache::new(1024 * 1024);
+        let mut settings = Settings::new(0);
+        settings.cache = Some(cache.clone());
+        let body = "cached ".repeat(200);
+        let mut response = Response::new(body.clone());
+        response
+            .headers_mut()
+            .insert(header::ETAG, header::HeaderValue::from_static("\"v1\""));
+
+        let (parts, compressed) = respond(response, Some(Codec::Gzip), settings.clone());
+        assert!(parts.headers.get(header::CONTENT_LENGTH).is_none());
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.size(), compressed.len());
+
+        // The inner body is ignored on a hit
+        let hit = make_response_with_headers("", [("etag", "\"v1\"")]);
+        let (parts, cached) = respond(hit, Some(Codec::Gzip), settings.clone());
+        assert_eq!(cached, compressed);
+        assert_eq!(parts.headers[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(
+            parts.headers[header::CONTENT_LENGTH],
+            compressed.len().to_string()
+        );
+        assert_eq!(gunzip(&cached), body.as_bytes());
+
+        // Another ETag is a miss
+        let other = make_response_with_headers("other", [("etag", "\"v2\"")]);
+        let (_, other) = respond(other, Some(Codec::Gzip), settings);
+        assert_eq!(gunzip(&other), b"other");
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_cache_skips_responses_without_key() {
+        let cache = CompressionCache::new(1024 * 1024);
+        let mut settings = Settings::new(0);
+        settings.cache = Some(cache.clone());
+
+        respond(
+            make_response("no etag"),
+            Some(Codec::Gzip),
+            settings.clone(),
+        );
+        let weak = make_response_with_headers("weak", [("etag", "W/\"v1\"")]);
+        respond(weak, Some(Codec::Gzip), settings.clone());
+        let streaming = make_response_with_headers(
+            "data: event",
+            [("etag", "\"v1\""), ("content-type", "text/event-stream")],
+        );
+        respond(streaming, Some(Codec::Gzip), settings.clone());
+        let uncompressed = make_response_with_headers("identity", [("etag", "\"v1\"")]);
+        respond(uncompressed, None, settings);
+
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_cache_skips_bodies_with_trailers() {
+        use http_body_util::BodyExt;
+
+        let cache = CompressionCache::new(1024 * 1024);
+        let mut settings = Settings::new(0);
+        settings.cache = Some(cache.clone());
+
+        let mut trailers = header::HeaderMap::new();
+        trailers.insert("x-checksum", header::HeaderValue::from_static("abc123"));
+        let body = String::from("with trailers").with_trailers(async move { Some(Ok(trailers)) });
+        let mut response = Response::new(body);
+        response
+            .headers_mut()
+            .insert(header::ETAG, header::HeaderValue::from_static("\"v1\""));
+        respond(response, Some(Codec::Gzip), settings);
+
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_cache_with_custom_key() {
+        let cache = CompressionCache::new(1024 * 1024).key_with(
+            |_: &RequestInfo, res: &ResponseInfo<'_>| {
+                res.headers()
+                    .get("x-cache-key")
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_owned)
+            },
+        );
+        let mut settings = Settings::new(0);
+        settings.cache = Some(cache.clone());
+
+        let response = make_response_with_headers("keyed", [("x-cache-key", "k")]);
+        respond(response, Some(Codec::Gzip), settings.clone());
+        assert_eq!(cache.len(), 1);
+
+        let hit = make_response_with_headers("", [("x-cache-key", "k")]);
+        let (_, body) = respond(hit, Some(Codec::Gzip), settings);
+        assert_eq!(gunzip(&body), b"keyed");
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_etag_weakened_when_compressing() {
+        // Tags are kept by default
+        let response = make_response_with_headers("hello world", [("etag", "\"abc\"")]);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
+        assert_eq!(wrapped.headers()[header::ETAG], "\"abc\"");
+
+        let mut settings = Settings::new(0);
+        settings.etag = crate::ETagMode::Weaken;
+        let response = make_response_with_headers("hello world", [("etag", "\"abc\"")]);
+        let wrapped = wrap_with(response, Some(Codec::Gzip), settings.clone());
+        assert_eq!(wrapped.headers()[header::ETAG], "W/\"abc\"");
+
+        let response = make_response_with_headers("hello world", [("etag", "\"abc\"")]);
+        let wrapped = wrap_with(response, None, settings);
+        assert_eq!(wrapped.headers()[header::ETAG], "\"abc\"");
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_etag_suffixed_when_compressing() {
+        let mut settings = Settings::new(0);
+        settings.etag = crate::ETagMode::Suffix;
+        let response = make_response_with_headers("hello world", [("etag", "\"abc\"")]);
+        let wrapped = wrap_with(response, Some(Codec::Gzip), settings);
+        assert_eq!(wrapped.headers()[header::ETAG], "\"abc-gzip\"");
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_etag_rewritten_on_not_modified() {
+        let not_modified = |validated| {
+            let mut settings = Settings::new(0);
+            settings.etag = crate::ETagMode::Weaken;
+            let mut response = make_response_with_headers("", [("etag", "\"abc\"")]);
+            *response.status_mut() = StatusCode::NOT_MODIFIED;
+            let future = ResponseFuture::new(
+                std::future::ready(Ok::<_, std::convert::Infallible>(response)),
+                Some(Codec::Gzip),
+                validated,
+                None,
+                RequestInfo::default(),
+                None,
+                Arc::new(settings),
+            );
+            poll_future(future, 0).unwrap().unwrap()
+        };
+
+        let response = not_modified(vec![("abc".to_owned(), "gzip")]);
+        assert_eq!(response.headers()[header::ETAG], "W/\"abc\"");
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+
+        let response = not_modified(vec![("def".to_owned(), "gzip")]);
+        assert_eq!(response.headers()[header::ETAG], "\"abc\"");
+
+        let response = not_modified(Validated::new());
+        assert_eq!(response.headers()[header::ETAG], "\"abc\"");
+    }
+
+    /// Runs a response to a request for the path through a `ResponseFuture`
+    /// using the dictionary, and collects its body.
+    fn respond_with_dictionary(
+        response: Response<String>,
+        accepted_codec: Option<Codec>,
+        dictionary: Option<(Codec, Arc<Dictionary>)>,
+        path: &str,
+        settings: Settings,
+    ) -> (http::response::Parts, bytes::Bytes) {
+        use http_body_util::BodyExt;
+
+        let request = http::Request::get(path).body(()).unwrap();
+        let future = ResponseFuture::new(
+            std::future::ready(Ok::<_, std::convert::Infallible>(response)),
+            accepted_codec,
+            Validated::new(),
+            dictionary,
+            RequestInfo::from_request(&request),
+            None,
+            Arc::new(settings),
+        );
+        let response = poll_future(future, 10).unwrap().unwrap();
+        let (parts, body) = response.into_parts();
+        let body = poll_future(body.collect(), 10).unwrap().unwrap();
+        (parts, body.to_bytes())
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn test_dictionary_compression_zstd() {
+        use crate::body::tests::decode;
+        use compression_codecs::zstd::ZstdDecoder;
+
+        let dictionary = Arc::new(Dictionary::new("/d", "/*", "shared dictionary ".repeat(20)));
+        let body = "shared dictionary ".repeat(10);
+        let response = Response::new(body.clone());
+        let (parts, compressed) = respond_with_dictionary(
+            response,
+            None,
+            Some((Codec::Zstd, dictionary.clone())),
+            "/page",
+            Settings::new(0),
+        );
+
+        assert_eq!(parts.headers[header::CONTENT_ENCODING], "dcz");
+        let vary: Vec<_> = parts.headers.get_all(header::VARY).iter().collect();
+        assert_eq!(vary, ["accept-encoding", "available-dictionary"]);
+
+        let (header, stream) = compressed.split_at(40);
+        assert_eq!(&header[..8], crate::dictionary::DCZ_MAGIC);
+        assert_eq!(&header[8..], dictionary.hash());
+        let decoder = ZstdDecoder::new_with_dict(dictionary.bytes()).unwrap();
+        assert_eq!(decode(Box::new(decoder), stream), body.as_bytes());
+    }
+
+    #[test]
+    #[cfg(feature = "brotli")]
+    fn test_dictionary_compression_brotli() {
+        use std::io::Read;
+
+        let dictionary = Arc::new(Dictionary::new("/d", "/*", "shared dictionary ".repeat(20)));
+        let body = "shared dictionary ".repeat(10);
+        let response = Response::new(body.clone());
+        let (parts, compressed) = respond_with_dictionary(
+            response,
+            None,
+            Some((Codec::Brotli, dictionary.clone())),
+            "/page",
+            Settings::new(0),
+        );
+
+        assert_eq!(parts.headers[header::CONTENT_ENCODING], "dcb");
+        let (header, stream) = compressed.split_at(36);
+        assert_eq!(&header[..4], crate::dictionary::DCB_MAGIC);
+        assert_eq!(&header[4..], dictionary.hash());
+        let mut decoder = brotli::reader::Decompressor::new_with_custom_dict(
+            stream,
+            4096,
+            dictionary.bytes().to_vec().into(),
+        );
+        let mut decoded = Vec::new();
+        decoder.read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, body.as_bytes());
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_unusable_dictionary_falls_back_to_negotiated_codec() {
+        // Gzip can't compress with a dictionary
+        let dictionary = Arc::new(Dictionary::new("/d", "/*", "dictionary"));
+        let (parts, compressed) = respond_with_dictionary(
+            make_response("hello world"),
+            Some(Codec::Gzip),
+            Some((Codec::Gzip, dictionary.clone())),
+            "/page",
+            Settings::new(0),
+        );
+        assert_eq!(parts.headers[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(parts.headers[header::VARY], "accept-encoding");
+        assert_eq!(gunzip(&compressed), b"hello world");
+
+        // Without a negotiated codec, the response isn't compressed
+        let (parts, body) = respond_with_dictionary(
+            make_response("hello world"),
+            None,
+            Some((Codec::Gzip, dictionary)),
+            "/page",
+            Settings::new(0),
+        );
+        assert!(parts.headers.get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(body, "hello world");
+    }
+
+    #[test]
+    fn test_use_as_dictionary_header() {
+        let mut settings = Settings::new(0);
+        settings.dictionaries.push(Arc::new(
+            Dictionary::new("/d", "/app/*", "dictionary").id("v1"),
+        ));
+
+        let (parts, _) = respond_with_dictionary(
+            make_response("dictionary"),
+            None,
+            None,
+            "/d",
+            settings.clone(),
+        );
+        assert_eq!(
+            parts.headers[USE_AS_DICTIONARY],
+            "match=\"/app/*\", id=\"v1\""
+        );
+
+        let (parts, _) = respond_with_dictionary(
+            make_response("other"),
+            None,
+            None,
+            "/app/x",
+            settings.clone(),
+        );
+        assert!(parts.headers.get(USE_AS_DICTIONARY).is_none());
+
+        let mut not_found = make_response("missing");
+        *not_found.status_mut() = StatusCode::NOT_FOUND;
+        let (parts, _) = respond_with_dictionary(not_found, None, None, "/d", settings);
+        assert!(parts.headers.get(USE_AS_DICTIONARY).is_none());
+    }
+
+    #[test]
+    fn test_custom_codec() {
+        use crate::content_encoder::tests::COPY;
+
+        let response = make_response_with_headers("hello world", [("etag", "\"abc\"")]);
+        let (parts, body) = respond(response, Some(Codec::Custom(&COPY)), Settings::new(0));
+        assert_eq!(parts.headers[header::CONTENT_ENCODING], "copy");
+        assert_eq!(parts.headers[header::VARY], "accept-encoding");
+        assert_eq!(parts.headers[header::ETAG], "\"abc\"");
+        assert_eq!(body, "hello world");
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_no_compression_extension() {
+        let mut response = make_response("hello world");
+        response.extensions_mut().insert(crate::NoCompression);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
+        assert!(wrapped.headers().get(header::CONTENT_ENCODING).is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_breach_skips_cross_site_html() {
+        let mut settings = Settings::new(0);
+        settings.breach = Some(crate::BreachMitigation::new());
+        let request = |site| {
+            let request = http::Request::get("/")
+                .header("sec-fetch-site", site)
+                .body(())
+                .unwrap();
+            RequestInfo::from_request(&request)
+        };
+        let html = || make_response_with_headers("hello world", [("content-type", "text/html")]);
+
+        let wrapped = wrap_request(html(), Some(Codec::Gzip), &request("cross-site"), &settings);
+        assert!(wrapped.headers().get(header::CONTENT_ENCODING).is_none());
+
+        let wrapped = wrap_request(
+            html(),
+            Some(Codec::Gzip),
+            &request("same-origin"),
+            &settings,
+        );
+        assert_eq!(wrapped.headers()[header::CONTENT_ENCODING], "gzip");
+        let vary: Vec<_> = wrapped.headers().get_all(header::VARY).iter().collect();
+        assert_eq!(vary, ["accept-encoding", "sec-fetch-site"]);
+
+        let json = make_response_with_headers("{}", [("content-type", "application/json")]);
+        let wrapped = wrap_request(json, Some(Codec::Gzip), &request("cross-site"), &settings);
+        assert_eq!(wrapped.headers()[header::CONTENT_ENCODING], "gzip");
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_breach_html_comment_padding() {
+        let cache = CompressionCache::new(1024 * 1024);
+        let mut settings = Settings::new(0);
+        settings.cache = Some(cache.clone());
+        settings.breach =
+            Some(crate::BreachMitigation::new().padding(crate::Padding::HtmlComment, 64));
+        let html = make_response_with_headers(
+            "<p>hello</p>",
+            [("content-type", "text/html"), ("etag", "\"v1\"")],
+        );
+
+        let (parts, body) = respond(html, Some(Codec::Gzip), settings.clone());
+        assert_eq!(parts.headers[header::CONTENT_ENCODING], "gzip");
+        let body = String::from_utf8(gunzip(&body)).unwrap();
+        let padding = body.strip_prefix("<p>hello</p><!-- ").unwrap();
+        assert!(padding.ends_with(" -->"));
+        assert!(padding.len() <= 64 + 4);
+        assert!(cache.is_empty());
+
+        // Other content types aren't padded
+        let text = make_response_with_headers("hello", [("content-type", "text/plain")]);
+        let (_, body) = respond(text, Some(Codec::Gzip), settings);
+        assert_eq!(gunzip(&body), b"hello");
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_breach_header_padding() {
+        let name = header::HeaderName::from_static("x-padding");
+        let mut settings = Settings::new(0);
+        settings.breach =
+            Some(crate::BreachMitigation::new().padding(crate::Padding::Header(name.clone()), 16));
+
+        let wrapped = wrap_with(
+            make_response("hello world"),
+            Some(Codec::Gzip),
+            settings.clone(),
+        );
+        assert!(wrapped.headers()[&name].len() <= 16);
+
+        let wrapped = wrap_with(make_response("hello world"), None, settings);
+        assert!(wrapped.headers().get(&name).is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_override_disable() {
+        let mut response = make_response("hello world");
+        response
+            .extensions_mut()
+            .insert(CompressionOverride::Disable);
+        let wrapped = wrap(response, Some(Codec::Gzip), 0);
+        assert!(wrapped.headers().get(header::CONTENT_ENCODING).is_none());
+        assert!(wrapped.extensions().get::<CompressionOverride>().is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_override_force_codec() {
+        let forced = || {
+            let mut response = make_response_with_headers("hi", [("content-type", "image/png")]);
+            response
+                .extensions_mut()
+                .insert(CompressionOverride::ForceCodec(Codec::Gzip));
+            response
+        };
+        let request = |accept_encoding| {
+            let request = http::Request::get("/")
+                .header(header::ACCEPT_ENCODING, accept_encoding)
+                .body(())
+                .unwrap();
+            RequestInfo::from_request(&request)
+        };
+        let settings = Settings::new(1024);
+
+        // Compressed despite the content type, the minimum size and the
+        // negotiated codec
+        let wrapped = wrap_request(forced(), None, &request("gzip"), &settings);
+        assert_eq!(wrapped.headers()[header::CONTENT_ENCODING], "gzip");
+        assert!(wrapped.extensions().get::<CompressionOverride>().is_none());
+
+        let wrapped = wrap_request(forced(), None, &request("deflate, gzip;q=0"), &settings);
+        assert!(wrapped.headers().get(header::CONTENT_ENCODING).is_none());
+
+        let mut encoded = forced();
+        encoded.headers_mut().insert(
+            header::CONTENT_ENCODING,
+            header::HeaderValue::from_static("br"),
+        );
+        let wrapped = wrap_request(encoded, None, &request("gzip"), &settings);
+        assert_eq!(wrapped.headers()[header::CONTENT_ENCODING], "br");
+    }
+
+    /// Selects the codec of a response forcing gzip, for a request accepting
+    /// gzip from another site.
+    #[cfg(feature = "gzip")]
+    fn select_forced(
+        mut response: Response<String>,
+        policy: Option<&CompressionPolicy>,
+        settings: &Settings,
+    ) -> Option<Codec> {
+        response
+            .extensions_mut()
+            .insert(CompressionOverride::ForceCodec(Codec::Gzip));
+        let request = http::Request::get("/")
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .header("sec-fetch-site", "cross-site")
+            .body(())
+            .unwrap();
+        let (parts, body) = response.into_parts();
+        let request = RequestInfo::from_request(&request);
+        select_codec(&parts, &body, None, policy, &request, settings).ok()
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_force_codec_respects_no_compression() {
+        let settings = Settings::new(0);
+        assert_eq!(
+            select_forced(make_response("hi"), None, &settings),
+            Some(Codec::Gzip)
+        );
+
+        let mut response = make_response("hi");
+        response.extensions_mut().insert(crate::NoCompression);
+        assert_eq!(select_forced(response, None, &settings), None);
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_force_codec_respects_breach_mitigation() {
+        let mut settings = Settings::new(0);
+        settings.breach = Some(crate::BreachMitigation::new());
+        let html = || make_response_with_headers("hi", [("content-type", "text/html")]);
+        assert_eq!(select_forced(html(), None, &settings), None);
+
+        let json = make_response_with_headers("{}", [("content-type", "application/json")]);
+        assert_eq!(select_forced(json, None, &settings), Some(Codec::Gzip));
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_force_codec_respects_disabled_codec() {
+        let mut settings = Settings::new(0);
+        settings.preference.disable(Codec::Gzip);
+        assert_eq!(select_forced(make_response("hi"), None, &settings), None);
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_force_codec_respects_policy() {
+        let settings = Settings::new(0);
+        let disabled = CompressionPolicy::disabled();
+        assert_eq!(
+            select_forced(make_response("hi"), Some(&disabled), &settings),
+            None
+        );
+
+        let gzip = CompressionPolicy::new().codecs([Codec::Gzip]);
+        assert_eq!(
+            select_forced(make_response("hi"), Some(&gzip), &settings),
+            Some(Codec::Gzip)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_override_level() {
+        let body = "compressible ".repeat(100);
+        let mut response = Response::new(body.clone());
+        response
+            .extensions_mut()
+            .insert(CompressionOverride::Level(CompressionLevel::Precise(0)));
+        let (parts, stored) = respond(response, Some(Codec::Gzip), Settings::new(0));
+        let (_, compressed) = respond(
+            Response::new(body.clone()),
+            Some(Codec::Gzip),
+            Settings::new(0),
+        );
+
+        assert!(parts.extensions.get::<CompressionOverride>().is_none());
+        assert!(stored.len() > body.len());
+        assert!(compressed.len() < body.len());
+        assert_eq!(gunzip(&stored), body.as_bytes());
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_override_always_flush() {
+        let mut response =
+            make_response_with_headers("data", [("content-type", "text/event-stream")]);
+        response
+            .extensions_mut()
+            .insert(CompressionOverride::AlwaysFlush(false));
+        match &wrap(response, Some(Codec::Gzip), 0).body().kind {
+            Kind::Compressed { state, .. } => assert!(!state.always_flush()),
+            _ => panic!("Expected compressed body"),
+        }
+
+        let cache = CompressionCache::new(1024 * 1024);
+        let mut settings = Settings::new(0);
+        settings.cache = Some(cache.clone());
+        let mut response = make_response_with_headers("data", [("etag", "\"v1\"")]);
+        response
+            .extensions_mut()
+            .insert(CompressionOverride::AlwaysFlush(true));
+        let (parts, body) = respond(response, Some(Codec::Gzip), settings);
+        assert!(parts.extensions.get::<CompressionOverride>().is_none());
+        assert_eq!(gunzip(&body), b"data");
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_passthrough_reason() {
+        // The minimum size comes from the request's policy
+        let reason = |response: Response<String>, accept_encoding, min_size| {
+            let request = http::Request::get("/")
+                .header(header::ACCEPT_ENCODING, accept_encoding)
+                .body(())
+                .unwrap();
+            let settings = Settings::new(0);
+            let policy = CompressionPolicy::new().min_size(min_size);
+            let accepted_codec = settings.preference.negotiate_headers(request.headers());
+            let (parts, body) = response.into_parts();
+            select_codec(
+                &parts,
+                &body,
+                accepted_codec,
+                Some(&policy),
+                &RequestInfo::from_request(&request),
+                &settings,
+            )
+            .unwrap_err()
+        };
+
+        let mut no_content = make_response("");
+        *no_content.status_mut() = StatusCode::NO_CONTENT;
+        assert_eq!(reason(no_content, "gzip", 0), "status without body");
+        assert_eq!(
+            reason(make_response("hello"), "identity", 0),
+            "no accepted content coding"
+        );
+        let encoded = make_response_with_headers("hello", [("content-encoding", "br")]);
+        assert_eq!(reason(encoded, "gzip", 0), "content-encoding present");
+        let range = make_response_with_headers("hello", [("content-range", "bytes 0-4/10")]);
+        assert_eq!(reason(range, "gzip", 0), "content-range present");
+        let image = make_response_with_headers("hello", [("content-type", "image/png")]);
+        assert_eq!(reason(image, "gzip", 0), "content type not compressible");
+        assert_eq!(
+            reason(make_response("hello"), "gzip", 1024),
+            "body below minimum size"
+        );
+
+        let mut forced = make_response("hello");
+        forced
+            .extensions_mut()
+            .insert(CompressionOverride::ForceCodec(Codec::Gzip));
+        assert_eq!(reason(forced, "deflate", 0), "forced codec not accepted");
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_observer_counts_finished_body() {
+        let counters = crate::CompressionCounters::new();
+        let mut settings = Settings::new(0);
+        settings.observer = Some(Arc::new(counters.clone()));
+        let body = "observed ".repeat(100);
+
+        let (_, compressed) = respond(Response::new(body.clone()), Some(Codec::Gzip), settings);
+        assert_eq!(counters.finished(), 1);
+        assert_eq!(counters.dropped(), 0);
+        assert_eq!(counters.input_bytes(), body.len() as u64);
+        assert_eq!(counters.output_bytes(), compressed.len() as u64);
+        assert_eq!(counters.flushes(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_observer_counts_flushes_and_dropped_body() {
+        let stats = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut settings = Settings::new(0);
+        let observed = stats.clone();
+        settings.observer = Some(Arc::new(move |s: &crate::CompressionStats| {
+            observed.lock().unwrap().push(*s)
+        }));
+
+        let mut response = Response::new(UnknownSize::new(&["one", "two"]));
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static("text/event-stream"),
+        );
+        let (_, compressed) = respond(response, Some(Codec::Gzip), settings.clone());
+        assert_eq!(gunzip(&compressed), b"onetwo");
+
+        // A body dropped before it ends is reported as not finished
+        drop(wrap_with(
+            make_response("hello"),
+            Some(Codec::Gzip),
+            settings.clone(),
+        ));
+        // Uncompressed bodies aren't observed
+        drop(wrap_with(make_response("hello"), None, settings));
+
+        let stats = stats.lock().unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].codec, Codec::Gzip);
+        assert!(stats[0].finished);
+        assert_eq!(stats[0].flushes, 2);
+        assert_eq!(stats[0].input_bytes, 6);
+        assert!(!stats[1].finished);
+        assert_eq!(stats[1].input_bytes, 0);
+    }
 }
@@ -1,4 +1,16 @@
+use crate::accept_encoding::is_token;
+use crate::breach::BreachMitigation;
+use crate::cache::CompressionCache;
+use crate::codec::{Codec, CodecLevels, CompressionLevel};
+use crate::content_encoder::ContentEncoder;
+use crate::content_type::ContentTypeFilter;
+use crate::dictionary::Dictionary;
+use crate::etag::ETagMode;
+use crate::metrics::CompressionObserver;
+use crate::predicate::{MinSize, Predicate};
 use crate::service::CompressionService;
+use crate::settings::Settings;
+use std::sync::Arc;
 use tower::Layer;
 
 /// Default minimum body size for compression (approximately 1 MTU).
@@ -10,7 +22,7 @@ pub const DEFAULT_MIN_SIZE: usize = 860;
 /// based on the client's Accept-Encoding header.
 #[derive(Debug, Clone)]
 pub struct CompressionLayer {
-    min_size: usize,
+    settings: Arc<Settings>,
 }
 
 impl CompressionLayer {
@@ -19,16 +31,224 @@ impl CompressionLayer {
     /// The default minimum size for compression is 860 bytes.
     pub fn new() -> Self {
         Self {
-            min_size: DEFAULT_MIN_SIZE,
+            settings: Arc::new(Settings::default()),
         }
     }
 
+    /// Returns the settings.
+    pub(crate) fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Returns the settings for modification.
+    fn settings_mut(&mut self) -> &mut Settings {
+        Arc::make_mut(&mut self.settings)
+    }
+
     /// Sets the minimum body size required for compression.
     ///
     /// Responses with a known Content-Length smaller than this value
     /// will not be compressed.
     pub fn min_size(mut self, size: usize) -> Self {
-        self.min_size = size;
+        self.settings_mut().rules.min_size = MinSize::new(size);
+        self
+    }
+
+    /// Sets whether bodies of unknown length are buffered before deciding
+    /// whether to compress them.
+    ///
+    /// Without a `Content-Length` header or an exact size hint, the body's
+    /// size isn't known up front and it is always compressed. When enabled,
+    /// up to the minimum size is read from the body first, and the response
+    /// is only compressed if the body turns out to be at least that large.
+    /// The response is returned once that decision has been made, which
+    /// delays it until the body produces enough data or ends.
+    ///
+    /// Streaming responses (`text/event-stream`, gRPC, or with
+    /// `X-Accel-Buffering: no`) are never buffered. Disabled by default.
+    pub fn buffer_unknown_size(mut self, enabled: bool) -> Self {
+        self.settings_mut().buffer_unknown_size = enabled;
+        self
+    }
+
+    /// Sets how the `ETag` of compressed responses is rewritten.
+    ///
+    /// Tags are left unchanged by default; see [`ETagMode`] for details.
+    pub fn etag(mut self, mode: ETagMode) -> Self {
+        self.settings_mut().etag = mode;
+        self
+    }
+
+    /// Sets a cache of compressed bodies, so responses that were already
+    /// compressed are served from memory.
+    ///
+    /// See [`CompressionCache`] for which responses are cached.
+    pub fn cache(mut self, cache: CompressionCache) -> Self {
+        self.settings_mut().cache = Some(cache);
+        self
+    }
+
+    /// Adds a shared dictionary, used to compress responses for clients that
+    /// have it and accept `dcz` or `dcb`.
+    ///
+    /// Responses to requests for the dictionary's path get a
+    /// `Use-As-Dictionary` header. See [`Dictionary`] for details.
+    pub fn dictionary(mut self, dictionary: Dictionary) -> Self {
+        self.settings_mut().dictionaries.push(Arc::new(dictionary));
+        self
+    }
+
+    /// Enables mitigations against BREACH for responses mixing secrets with
+    /// reflected input.
+    ///
+    /// See [`BreachMitigation`] for details. Disabled by default.
+    pub fn breach_mitigation(mut self, mitigation: BreachMitigation) -> Self {
+        self.settings_mut().breach = Some(mitigation);
+        self
+    }
+
+    /// Sets an observer called with the statistics of every compressed body,
+    /// once it ends or is dropped.
+    ///
+    /// See [`CompressionObserver`] for details, and [`CompressionCounters`]
+    /// for an observer adding them up.
+    ///
+    /// [`CompressionCounters`]: crate::CompressionCounters
+    pub fn observer<O>(mut self, observer: O) -> Self
+    where
+        O: CompressionObserver,
+    {
+        self.settings_mut().observer = Some(Arc::new(observer));
+        self
+    }
+
+    /// Sets the filter deciding which content types are compressed.
+    ///
+    /// See [`ContentTypeFilter`] for the default list.
+    pub fn content_types(mut self, filter: ContentTypeFilter) -> Self {
+        self.settings_mut().rules.content_types = filter;
+        self
+    }
+
+    /// Compresses content types matching the pattern, such as `text/*`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pattern isn't `type/subtype`, `type/*` or `*/*`.
+    pub fn allow_content_type(mut self, pattern: &str) -> Self {
+        let rules = &mut self.settings_mut().rules;
+        rules.content_types = std::mem::take(&mut rules.content_types).allow(pattern);
+        self
+    }
+
+    /// Doesn't compress content types matching the pattern, such as `video/*`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pattern isn't `type/subtype`, `type/*` or `*/*`.
+    pub fn deny_content_type(mut self, pattern: &str) -> Self {
+        let rules = &mut self.settings_mut().rules;
+        rules.content_types = std::mem::take(&mut rules.content_types).deny(pattern);
+        self
+    }
+
+    /// Sets whether responses with `Cache-Control: no-transform` are left
+    /// uncompressed.
+    ///
+    /// This is enabled by default, as RFC 9110 forbids intermediaries from
+    /// transforming such responses.
+    pub fn respect_no_transform(mut self, respect: bool) -> Self {
+        self.settings_mut().rules.respect_no_transform = respect;
+        self
+    }
+
+    /// Sets the compression level used by every codec.
+    ///
+    /// This overrides any level previously set for an individual codec.
+    pub fn level(mut self, level: CompressionLevel) -> Self {
+        self.settings_mut().levels = CodecLevels::all(level);
+        self
+    }
+
+    /// Sets the compression level used for zstd.
+    #[cfg(feature = "zstd")]
+    pub fn zstd_level(mut self, level: CompressionLevel) -> Self {
+        self.settings_mut().levels.zstd = level;
+        self
+    }
+
+    /// Sets the compression level used for brotli.
+    #[cfg(feature = "brotli")]
+    pub fn brotli_level(mut self, level: CompressionLevel) -> Self {
+        self.settings_mut().levels.brotli = level;
+        self
+    }
+
+    /// Sets the compression level used for gzip.
+    #[cfg(feature = "gzip")]
+    pub fn gzip_level(mut self, level: CompressionLevel) -> Self {
+        self.settings_mut().levels.gzip = level;
+        self
+    }
+
+    /// Sets the compression level used for deflate.
+    #[cfg(feature = "deflate")]
+    pub fn deflate_level(mut self, level: CompressionLevel) -> Self {
+        self.settings_mut().levels.deflate = level;
+        self
+    }
+
+    /// Sets the server's order of preference between codecs.
+    ///
+    /// When the client accepts several codecs with the same quality, the
+    /// one listed first is used. Codecs that aren't listed are tried after
+    /// the listed ones, in the default order (zstd, brotli, gzip, deflate).
+    pub fn prefer<I>(mut self, codecs: I) -> Self
+    where
+        I: IntoIterator<Item = Codec>,
+    {
+        self.settings_mut().preference.prefer(codecs);
+        self
+    }
+
+    /// Disables a codec, so it is never used even if the client accepts it.
+    pub fn disable(mut self, codec: Codec) -> Self {
+        self.settings_mut().preference.disable(codec);
+        self
+    }
+
+    /// Registers a custom content coding, negotiated like the built-in codecs.
+    ///
+    /// A coding with the same name as an enabled codec replaces it, taking
+    /// its place in the order of preference; this is how a built-in codec
+    /// is swapped for another implementation. Other codings are placed by
+    /// their [`ContentEncoder::priority`], and can be moved with
+    /// [`prefer`](Self::prefer) afterwards. Custom codings are compressed at
+    /// the level set with [`level`](Self::level).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the name isn't a valid content coding token.
+    pub fn register(mut self, encoder: &'static dyn ContentEncoder) -> Self {
+        assert!(
+            is_token(encoder.name()),
+            "invalid content coding: {:?}",
+            encoder.name()
+        );
+        self.settings_mut().preference.register(encoder);
+        self
+    }
+
+    /// Sets an additional rule deciding whether a response is compressed.
+    ///
+    /// The predicate is evaluated after the built-in rules, so a response is
+    /// only compressed when both allow it. Setting a predicate replaces any
+    /// previously set one; use [`Predicate::and`] to combine several.
+    pub fn compress_when<P>(mut self, predicate: P) -> Self
+    where
+        P: Predicate,
+    {
+        self.settings_mut().predicate = Some(Arc::new(predicate));
         self
     }
 }
@@ -43,6 +263,6 @@ impl<S> Layer<S> for CompressionLayer {
     type Service = CompressionService<S>;
 
     fn layer(&self, inner: S) -> Self::Service {
-        CompressionService::new(inner, self.min_size)
+        CompressionService::with_settings(inner, self.settings.clone())
     }
 }
@@ -14,15 +14,164 @@
 //!     .service(my_service);
 //! ```
 //!
+//! Compression levels can be tuned for all codecs or per codec:
+//!
+//! ```ignore
+//! use http_response_compression::{CompressionLayer, CompressionLevel};
+//!
+//! let layer = CompressionLayer::new()
+//!     .level(CompressionLevel::Fastest)
+//!     .brotli_level(CompressionLevel::Precise(5));
+//! ```
+//!
+//! The server's codec preference can be changed, and codecs disabled, at runtime:
+//!
+//! ```ignore
+//! use http_response_compression::{Codec, CompressionLayer};
+//!
+//! let layer = CompressionLayer::new()
+//!     .prefer([Codec::Brotli, Codec::Gzip])
+//!     .disable(Codec::Deflate);
+//! ```
+//!
+//! Other content codings, such as `lz4` or a hardware-accelerated `gzip`, can
+//! be plugged in by implementing [`ContentEncoder`] and registering it with
+//! [`CompressionLayer::register`]:
+//!
+//! ```ignore
+//! use http_response_compression::CompressionLayer;
+//!
+//! static LZ4: Lz4 = Lz4;
+//!
+//! let layer = CompressionLayer::new().register(&LZ4);
+//! ```
+//!
+//! Handlers and other middleware can reuse the same negotiation logic:
+//!
+//! ```ignore
+//! use http_response_compression::negotiate;
+//!
+//! let codec = negotiate(request.headers());
+//! ```
+//!
+//! Accept-Encoding is parsed following RFC 9110, including `*` wildcards,
+//! `q=0` exclusions and `identity` preferences. [`AcceptEncoding`] reports when
+//! a client refuses every available coding, so a handler can respond with
+//! `406 Not Acceptable`.
+//!
+//! Compressed bodies can be kept in memory with a [`CompressionCache`], keyed
+//! by a strong `ETag` or a custom key, so identical responses aren't compressed
+//! again:
+//!
+//! ```ignore
+//! use http_response_compression::{CompressionCache, CompressionLayer};
+//!
+//! let layer = CompressionLayer::new().cache(CompressionCache::new(64 * 1024 * 1024));
+//! ```
+//!
+//! Compression can be measured with a [`CompressionObserver`], called with the
+//! [`CompressionStats`] of every compressed body, such as the atomic
+//! [`CompressionCounters`]:
+//!
+//! ```ignore
+//! use http_response_compression::{CompressionCounters, CompressionLayer};
+//!
+//! let counters = CompressionCounters::new();
+//! let layer = CompressionLayer::new().observer(counters.clone());
+//! ```
+//!
+//! With the `tracing` feature, the middleware emits `DEBUG` events for the
+//! negotiated codec, the rule that left a response uncompressed and the
+//! statistics of every compressed body.
+//!
+//! Clients holding a shared [`Dictionary`] get responses compressed with it,
+//! using the `dcz` and `dcb` codings of Compression Dictionary Transport
+//! (RFC 9842):
+//!
+//! ```ignore
+//! use http_response_compression::{CompressionLayer, Dictionary};
+//!
+//! let layer = CompressionLayer::new()
+//!     .dictionary(Dictionary::new("/app.v1.js", "/app.*.js", dictionary_bytes));
+//! ```
+//!
+//! Responses mixing secrets with reflected input can opt out of compression
+//! with the [`NoCompression`] extension, and [`BreachMitigation`] adds random
+//! padding and skips compressing HTML for cross-site requests, against BREACH:
+//!
+//! ```ignore
+//! use http_response_compression::{BreachMitigation, CompressionLayer, Padding};
+//!
+//! let layer = CompressionLayer::new()
+//!     .breach_mitigation(BreachMitigation::new().padding(Padding::HtmlComment, 64));
+//! ```
+//!
+//! Handlers can override the codec, level or flushing of a single response
+//! with the [`CompressionOverride`] extension, which the middleware removes
+//! before returning the response:
+//!
+//! ```ignore
+//! use http_response_compression::{Codec, CompressionOverride};
+//!
+//! response.extensions_mut().insert(CompressionOverride::ForceCodec(Codec::Gzip));
+//! ```
+//!
+//! Middleware running before the compression layer, such as a router, can set
+//! a [`CompressionPolicy`] request extension to disable compression, restrict
+//! the codecs or change the minimum size for a single request.
+//!
+//! Static assets can be served from precompressed `.zst`, `.br` and `.gz`
+//! siblings with [`PrecompressedLayer`], falling back to the original file when
+//! no variant exists. [`precompress_dir`] generates the variants at build time
+//! with the same codecs and levels as a [`CompressionLayer`].
+//!
+//! Request bodies can be decompressed with [`RequestDecompressionLayer`], which
+//! rejects unsupported encodings with `415 Unsupported Media Type`:
+//!
+//! ```ignore
+//! use http_response_compression::RequestDecompressionLayer;
+//!
+//! let service = ServiceBuilder::new()
+//!     .layer(RequestDecompressionLayer::new())
+//!     .service(my_service);
+//! ```
+//!
+//! Clients can use [`DecompressionLayer`] to advertise the enabled codecs in
+//! `Accept-Encoding` and transparently decode responses:
+//!
+//! ```ignore
+//! use http_response_compression::DecompressionLayer;
+//!
+//! let client = ServiceBuilder::new()
+//!     .layer(DecompressionLayer::new())
+//!     .service(hyper_client);
+//! ```
+//!
+//! Both decompression layers can limit the decompressed size and expansion
+//! ratio of a body, which then fails with a [`DecompressionError`] instead of
+//! growing without bound.
+//!
 //! # Compression Rules
 //!
 //! The middleware will **not** compress responses when:
 //! - No supported `Accept-Encoding` is present in the request
+//! - The status never has a body (1xx, 204 and 304)
 //! - `Content-Encoding` header is already set
 //! - `Content-Range` header is present (range responses)
-//! - `Content-Type` starts with `image/` (except `image/svg+xml`)
-//! - `Content-Type` starts with `application/grpc` (except `application/grpc-web`)
-//! - `Content-Length` is below the minimum size threshold (default: 860 bytes)
+//! - `Cache-Control` contains `no-transform` (configurable)
+//! - `Content-Type` is already compressed: `image/*` (except `image/svg+xml`),
+//!   `video/*`, `audio/*`, WOFF fonts, `application/octet-stream` and archives
+//!   (configurable with [`ContentTypeFilter`])
+//! - The body is known to be below the minimum size threshold (default: 860 bytes),
+//!   from `Content-Length` or the body's size hint
+//! - The response has a [`NoCompression`] extension or a
+//!   [`CompressionOverride::Disable`]
+//!
+//! Bodies of unknown length are compressed regardless of their size, unless
+//! [`CompressionLayer::buffer_unknown_size`] is enabled.
+//!
+//! Additional rules can be added with [`CompressionLayer::compress_when`] and a
+//! [`Predicate`]; the built-in rules are available as predicates for reuse.
 //!
 //! The middleware will **always flush** after each chunk when:
 //! - `X-Accel-Buffering: no` header is present
@@ -36,6 +185,12 @@
 //! - `Content-Length` header is removed (compressed size is unknown)
 //! - `Accept-Ranges` header is removed
 //! - `Vary` header includes `Accept-Encoding`
+//! - The `ETag` is rewritten if configured with [`CompressionLayer::etag`];
+//!   conditional request headers are then rewritten back so the inner
+//!   service can still validate them
+//!
+//! Responses to `HEAD` requests get the same headers a `GET` would, but their
+//! (empty) body is passed through unchanged.
 
 #![deny(missing_docs)]
 
@@ -47,13 +202,54 @@
 )))]
 compile_error!("At least one compression codec feature must be enabled");
 
+mod accept_encoding;
 mod body;
+mod breach;
+mod cache;
 mod codec;
+mod content_encoder;
+mod content_type;
+mod decompression;
+mod decompression_body;
+mod dictionary;
+mod error;
+mod etag;
 mod future;
 mod layer;
+mod metrics;
+mod overrides;
+mod precompress;
+mod precompressed;
+mod predicate;
+mod request_decompression;
 mod service;
+mod settings;
 
+pub use accept_encoding::{AcceptEncoding, Negotiation};
 pub use body::CompressionBody;
+pub use breach::{BreachMitigation, NoCompression, Padding};
+pub use cache::CompressionCache;
+pub use codec::{Codec, CompressionLevel, negotiate};
+pub use compression_codecs::{DecodeV2, EncodeV2};
+pub use compression_core::util::{PartialBuffer, WriteBuffer};
+pub use content_encoder::ContentEncoder;
+pub use content_type::ContentTypeFilter;
+pub use decompression::{DecompressionFuture, DecompressionLayer, DecompressionService};
+pub use decompression_body::DecompressionBody;
+pub use dictionary::Dictionary;
+pub use error::DecompressionError;
+pub use etag::ETagMode;
 pub use future::ResponseFuture;
 pub use layer::CompressionLayer;
+pub use metrics::{CompressionCounters, CompressionObserver, CompressionStats};
+pub use overrides::{CompressionOverride, CompressionPolicy};
+pub use precompress::precompress_dir;
+pub use precompressed::{PrecompressedFuture, PrecompressedLayer, PrecompressedService};
+pub use predicate::{
+    And, MinSize, NoContentEncoding, NoContentRange, NoTransform, Not, Or, Predicate, RequestInfo,
+    ResponseInfo,
+};
+pub use request_decompression::{
+    RequestDecompressionFuture, RequestDecompressionLayer, RequestDecompressionService,
+};
 pub use service::CompressionService;
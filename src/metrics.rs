@@ -0,0 +1,225 @@
+use crate::codec::Codec;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Observes compressed bodies, to collect metrics about compression.
+///
+/// Set on a layer with [`CompressionLayer::observer`], it is called once for
+/// every body the middleware compresses: when the body ends, or when it is
+/// dropped before that. Closures taking [`CompressionStats`] implement this
+/// trait.
+///
+/// ```ignore
+/// use http_response_compression::{CompressionLayer, CompressionStats};
+///
+/// let layer = CompressionLayer::new().observer(|stats: &CompressionStats| {
+///     println!("{:?}: {} -> {} bytes", stats.codec, stats.input_bytes, stats.output_bytes);
+/// });
+/// ```
+///
+/// [`CompressionLayer::observer`]: crate::CompressionLayer::observer
+pub trait CompressionObserver: Send + Sync + 'static {
+    /// Called once the body is complete or dropped.
+    fn on_complete(&self, stats: &CompressionStats);
+}
+
+impl<F> CompressionObserver for F
+where
+    F: Fn(&CompressionStats) + Send + Sync + 'static,
+{
+    fn on_complete(&self, stats: &CompressionStats) {
+        self(stats)
+    }
+}
+
+/// Statistics about a compressed body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CompressionStats {
+    /// Codec the body was compressed with.
+    pub codec: Codec,
+    /// Number of bytes given to the encoder.
+    pub input_bytes: u64,
+    /// Number of compressed bytes produced by the encoder.
+    pub output_bytes: u64,
+    /// Number of times the encoder was flushed.
+    pub flushes: u64,
+    /// Time spent in the encoder.
+    pub encode_time: Duration,
+    /// Whether the whole body was compressed, rather than dropped early or
+    /// ended by an error.
+    pub finished: bool,
+}
+
+impl CompressionStats {
+    /// Creates empty statistics for a body compressed with the codec.
+    pub(crate) fn new(codec: Codec) -> Self {
+        Self {
+            codec,
+            input_bytes: 0,
+            output_bytes: 0,
+            flushes: 0,
+            encode_time: Duration::ZERO,
+            finished: false,
+        }
+    }
+}
+
+/// An observer adding up the statistics of every compressed body in atomic
+/// counters.
+///
+/// Clones share the same counters, so they can be read, for instance by a
+/// metrics exporter, while the layer updates them.
+///
+/// ```ignore
+/// use http_response_compression::{CompressionCounters, CompressionLayer};
+///
+/// let counters = CompressionCounters::new();
+/// let layer = CompressionLayer::new().observer(counters.clone());
+///
+/// let saved = counters.input_bytes() - counters.output_bytes();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CompressionCounters {
+    inner: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    finished: AtomicU64,
+    dropped: AtomicU64,
+    input_bytes: AtomicU64,
+    output_bytes: AtomicU64,
+    flushes: AtomicU64,
+    encode_nanos: AtomicU64,
+}
+
+impl CompressionCounters {
+    /// Creates counters starting at zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of bodies that were completely compressed.
+    pub fn finished(&self) -> u64 {
+        self.inner.finished.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of bodies that were dropped early or ended by an
+    /// error.
+    pub fn dropped(&self) -> u64 {
+        self.inner.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total number of bytes given to encoders.
+    pub fn input_bytes(&self) -> u64 {
+        self.inner.input_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total number of compressed bytes produced by encoders.
+    pub fn output_bytes(&self) -> u64 {
+        self.inner.output_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total number of encoder flushes.
+    pub fn flushes(&self) -> u64 {
+        self.inner.flushes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total time spent in encoders.
+    pub fn encode_time(&self) -> Duration {
+        Duration::from_nanos(self.inner.encode_nanos.load(Ordering::Relaxed))
+    }
+}
+
+impl CompressionObserver for CompressionCounters {
+    fn on_complete(&self, stats: &CompressionStats) {
+        let counters = &self.inner;
+        if stats.finished {
+            counters.finished.fetch_add(1, Ordering::Relaxed);
+        } else {
+            counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        counters
+            .input_bytes
+            .fetch_add(stats.input_bytes, Ordering::Relaxed);
+        counters
+            .output_bytes
+            .fetch_add(stats.output_bytes, Ordering::Relaxed);
+        counters.flushes.fetch_add(stats.flushes, Ordering::Relaxed);
+        let nanos = u64::try_from(stats.encode_time.as_nanos()).unwrap_or(u64::MAX);
+        counters.encode_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+}
+
+/// Statistics of a body being compressed, and the observer to report them to.
+pub(crate) struct Observation {
+    pub(crate) stats: CompressionStats,
+    observer: Option<Arc<dyn CompressionObserver>>,
+}
+
+impl Observation {
+    /// Starts observing a body compressed with the codec, unless there is
+    /// nothing to report the statistics to.
+    pub(crate) fn new(
+        codec: Codec,
+        observer: Option<Arc<dyn CompressionObserver>>,
+    ) -> Option<Self> {
+        if observer.is_none() && !cfg!(feature = "tracing") {
+            return None;
+        }
+        Some(Self {
+            stats: CompressionStats::new(codec),
+            observer,
+        })
+    }
+
+    /// Reports the statistics to the observer, and as a tracing event.
+    pub(crate) fn report(mut self, finished: bool) {
+        self.stats.finished = finished;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            codec = ?self.stats.codec,
+            input_bytes = self.stats.input_bytes,
+            output_bytes = self.stats.output_bytes,
+            flushes = self.stats.flushes,
+            encode_time = ?self.stats.encode_time,
+            finished,
+            "compressed body complete"
+        );
+        if let Some(observer) = &self.observer {
+            observer.on_complete(&self.stats);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::content_encoder::tests::COPY;
+
+    #[test]
+    fn test_counters() {
+        let counters = CompressionCounters::new();
+        let observer: Arc<dyn CompressionObserver> = Arc::new(counters.clone());
+        let mut observation =
+            Observation::new(Codec::Custom(&COPY), Some(observer.clone())).unwrap();
+        observation.stats.input_bytes = 100;
+        observation.stats.output_bytes = 40;
+        observation.stats.flushes = 2;
+        observation.stats.encode_time = Duration::from_micros(5);
+        observation.report(true);
+        let mut observation = Observation::new(Codec::Custom(&COPY), Some(observer)).unwrap();
+        observation.stats.input_bytes = 10;
+        observation.stats.output_bytes = 8;
+        observation.report(false);
+
+        assert_eq!(counters.finished(), 1);
+        assert_eq!(counters.dropped(), 1);
+        assert_eq!(counters.input_bytes(), 110);
+        assert_eq!(counters.output_bytes(), 48);
+        assert_eq!(counters.flushes(), 2);
+        assert_eq!(counters.encode_time(), Duration::from_micros(5));
+    }
+}
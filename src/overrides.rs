@@ -0,0 +1,205 @@
+use crate::codec::{Codec, CompressionLevel};
+use crate::predicate::MinSize;
+use std::sync::Arc;
+
+/// Response extension overriding how a single response is compressed.
+///
+/// Handlers insert it into the response's extensions, and the middleware
+/// removes it before returning the response:
+///
+/// ```ignore
+/// use http_response_compression::{CompressionLevel, CompressionOverride};
+///
+/// response
+///     .extensions_mut()
+///     .insert(CompressionOverride::Level(CompressionLevel::Best));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CompressionOverride {
+    /// Leaves the response uncompressed.
+    Disable,
+    /// Compresses with the codec if the client accepts it, even if the
+    /// server prefers another codec or the compression rules wouldn't
+    /// compress the response.
+    ///
+    /// Responses that can't be compressed, because they have no body, a
+    /// `Content-Encoding` or a `Content-Range`, are still left uncompressed,
+    /// as are responses to clients that don't accept the codec. So are
+    /// responses with a [`NoCompression`] extension or excluded by
+    /// [`BreachMitigation`], and codecs disabled on the layer or by the
+    /// request's [`CompressionPolicy`].
+    ///
+    /// [`NoCompression`]: crate::NoCompression
+    /// [`BreachMitigation`]: crate::BreachMitigation
+    ForceCodec(Codec),
+    /// Compresses with the given level instead of the configured one.
+    Level(CompressionLevel),
+    /// Sets whether the encoder is flushed after every chunk, instead of
+    /// detecting streaming responses from their headers.
+    AlwaysFlush(bool),
+}
+
+impl CompressionOverride {
+    /// Returns the overridden compression level, if any.
+    pub(crate) fn level(&self) -> Option<CompressionLevel> {
+        match self {
+            CompressionOverride::Level(level) => Some(*level),
+            _ => None,
+        }
+    }
+
+    /// Returns the overridden flushing behavior, if any.
+    pub(crate) fn always_flush(&self) -> Option<bool> {
+        match self {
+            CompressionOverride::AlwaysFlush(always_flush) => Some(*always_flush),
+            _ => None,
+        }
+    }
+}
+
+/// Request extension overriding the compression policy for a single request.
+///
+/// Middleware running before the compression layer, such as a router or an
+/// authentication layer, inserts it into the request's extensions:
+///
+/// ```ignore
+/// use http_response_compression::{Codec, CompressionPolicy};
+///
+/// // Health checks aren't worth compressing
+/// request.extensions_mut().insert(CompressionPolicy::disabled());
+///
+/// // Large exports are only compressed with gzip, whatever their size
+/// request
+///     .extensions_mut()
+///     .insert(CompressionPolicy::new().codecs([Codec::Gzip]).min_size(0));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompressionPolicy {
+    codecs: Option<Arc<[Codec]>>,
+    min_size: Option<usize>,
+}
+
+impl CompressionPolicy {
+    /// Creates a policy keeping the layer's configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a policy that leaves responses uncompressed.
+    pub fn disabled() -> Self {
+        Self::new().codecs([])
+    }
+
+    /// Restricts compression to the given codecs.
+    ///
+    /// Codecs the layer doesn't use aren't enabled, and the layer's order of
+    /// preference is kept.
+    pub fn codecs<I>(mut self, codecs: I) -> Self
+    where
+        I: IntoIterator<Item = Codec>,
+    {
+        self.codecs = Some(codecs.into_iter().collect());
+        self
+    }
+
+    /// Sets the minimum body size for compression, in bytes.
+    pub fn min_size(mut self, min_size: usize) -> Self {
+        self.min_size = Some(min_size);
+        self
+    }
+
+    /// Checks if the policy allows compressing with the codec.
+    pub(crate) fn allows(&self, codec: Codec) -> bool {
+        self.codecs
+            .as_ref()
+            .is_none_or(|codecs| codecs.contains(&codec))
+    }
+
+    /// Returns the minimum body size set by the policy, or else the given one.
+    pub(crate) fn min_size_or(&self, min_size: MinSize) -> MinSize {
+        self.min_size.map_or(min_size, MinSize::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CompressionLayer;
+    use crate::content_encoder::tests::{COPY, CopyCoding};
+    use http::{Request, Response, header};
+    use std::convert::Infallible;
+    use std::future::Future;
+    use std::task::{Context, Poll};
+    use tower::{Layer, ServiceExt};
+
+    static PREFERRED: CopyCoding = CopyCoding {
+        name: "preferred",
+        priority: 1,
+    };
+
+    fn block_on<T>(future: impl Future<Output = T>) -> T {
+        let mut future = std::pin::pin!(future);
+        let mut cx = Context::from_waker(std::task::Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    /// Sends a request accepting the given codings, with the given policy,
+    /// returning the response's `Content-Encoding`.
+    fn content_encoding(
+        accept_encoding: &'static str,
+        body: &'static str,
+        policy: Option<CompressionPolicy>,
+    ) -> Option<String> {
+        let service = CompressionLayer::new()
+            .min_size(16)
+            .register(&COPY)
+            .register(&PREFERRED)
+            .layer(tower::service_fn(move |_: Request<()>| async move {
+                Ok::<_, Infallible>(Response::new(body.to_owned()))
+            }));
+        let mut request = Request::builder()
+            .header(header::ACCEPT_ENCODING, accept_encoding)
+            .body(())
+            .unwrap();
+        if let Some(policy) = policy {
+            request.extensions_mut().insert(policy);
+        }
+        let response = block_on(service.oneshot(request)).unwrap();
+        response
+            .headers()
+            .get(header::CONTENT_ENCODING)
+            .map(|v| v.to_str().unwrap().to_owned())
+    }
+
+    #[test]
+    fn test_policy() {
+        let body = "a body long enough to be compressed";
+        assert_eq!(
+            content_encoding("copy, preferred", body, None).as_deref(),
+            Some("preferred")
+        );
+        assert_eq!(
+            content_encoding("copy, preferred", body, Some(CompressionPolicy::disabled())),
+            None
+        );
+
+        let copy = CompressionPolicy::new().codecs([Codec::Custom(&COPY)]);
+        assert_eq!(
+            content_encoding("copy, preferred", body, Some(copy.clone())).as_deref(),
+            Some("copy")
+        );
+        assert_eq!(content_encoding("preferred", body, Some(copy)), None);
+
+        assert_eq!(content_encoding("copy", "short", None), None);
+        assert_eq!(
+            content_encoding("copy", "short", Some(CompressionPolicy::new().min_size(0)))
+                .as_deref(),
+            Some("copy")
+        );
+    }
+}
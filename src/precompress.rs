@@ -0,0 +1,284 @@
+use crate::codec::{Codec, CompressionLevel};
+use crate::layer::CompressionLayer;
+use crate::precompressed::{content_type, variant_codecs};
+use compression_core::util::{PartialBuffer, WriteBuffer};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Writes precompressed `.zst`, `.br` and `.gz` siblings of every file in a
+/// directory, recursively, for [`PrecompressedLayer`] to serve.
+///
+/// The codecs, compression levels, content type filter and minimum size are
+/// taken from `layer`, so precompressed variants match what the layer would
+/// produce at runtime. A variant is only written if it is smaller than the
+/// original file; otherwise any existing variant is removed, so a stale one is
+/// never served. Files the layer wouldn't compress have their existing
+/// variants removed too. Existing variants and symbolic links are skipped.
+///
+/// Returns the paths of the variants written.
+///
+/// ```ignore
+/// use http_response_compression::{CompressionLayer, CompressionLevel, precompress_dir};
+///
+/// let layer = CompressionLayer::new().level(CompressionLevel::Best);
+/// precompress_dir("dist", &layer)?;
+/// ```
+///
+/// [`PrecompressedLayer`]: crate::PrecompressedLayer
+pub fn precompress_dir(
+    dir: impl AsRef<Path>,
+    layer: &CompressionLayer,
+) -> io::Result<Vec<PathBuf>> {
+    let mut written = Vec::new();
+    visit(dir.as_ref(), layer, &mut written)?;
+    Ok(written)
+}
+
+/// Precompresses the files in `dir` and its subdirectories.
+fn visit(dir: &Path, layer: &CompressionLayer, written: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            visit(&path, layer, written)?;
+        } else if file_type.is_file() && !is_variant(&path) {
+            precompress_file(&path, layer, written)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the variants of a single file.
+fn precompress_file(
+    path: &Path,
+    layer: &CompressionLayer,
+    written: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    let settings = layer.settings();
+    let compressible = path
+        .to_str()
+        .and_then(content_type)
+        .is_none_or(|content_type| settings.rules.content_types.is_compressible(content_type));
+    if !compressible {
+        return remove_variants(path);
+    }
+
+    let contents = fs::read(path)?;
+    if contents.len() < settings.rules.min_size.get() {
+        return remove_variants(path);
+    }
+
+    let codecs = variant_codecs();
+    for codec in settings.preference.iter().filter(|c| codecs.is_enabled(*c)) {
+        let Some(variant) = variant_path(path, codec) else {
+            continue;
+        };
+        let compressed = compress(codec, settings.levels.get(codec), &contents)?;
+        if compressed.len() < contents.len() {
+            fs::write(&variant, compressed)?;
+            written.push(variant);
+        } else {
+            remove_file(&variant)?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes every existing variant of a file.
+fn remove_variants(path: &Path) -> io::Result<()> {
+    for codec in variant_codecs().iter() {
+        if let Some(variant) = variant_path(path, codec) {
+            remove_file(&variant)?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes a file, if it exists.
+fn remove_file(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the path of the codec's variant of a file.
+fn variant_path(path: &Path, codec: Codec) -> Option<PathBuf> {
+    let mut variant = path.as_os_str().to_owned();
+    variant.push(".");
+    variant.push(codec.file_extension()?);
+    Some(variant.into())
+}
+
+/// Checks if the file is itself a precompressed variant.
+fn is_variant(path: &Path) -> bool {
+    let Some(extension) = path.extension() else {
+        return false;
+    };
+    variant_codecs()
+        .iter()
+        .filter_map(|codec| codec.file_extension())
+        .any(|variant| extension == variant)
+}
+
+/// Compresses a complete input with the codec.
+fn compress(codec: Codec, level: CompressionLevel, input: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = codec.encoder(level);
+    let mut input = PartialBuffer::new(input);
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut output = Vec::new();
+
+    while !input.unwritten().is_empty() {
+        let mut out = WriteBuffer::new_initialized(buffer.as_mut_slice());
+        encoder.encode(&mut input, &mut out)?;
+        let written = out.written_len();
+        output.extend_from_slice(&buffer[..written]);
+    }
+    loop {
+        let mut out = WriteBuffer::new_initialized(buffer.as_mut_slice());
+        let done = encoder.finish(&mut out)?;
+        let written = out.written_len();
+        output.extend_from_slice(&buffer[..written]);
+        if done {
+            return Ok(output);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Creates an empty directory for a test.
+    fn test_dir() -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "http-response-compression-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Returns bytes that don't compress.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_compress_round_trip() {
+        let input = "round trip ".repeat(1000);
+        for codec in variant_codecs().iter() {
+            let compressed = compress(codec, CompressionLevel::Default, input.as_bytes()).unwrap();
+            let body = crate::DecompressionBody::decompressed(
+                http_body_util::Full::new(bytes::Bytes::from(compressed)),
+                codec,
+            );
+            let mut collect = std::pin::pin!(http_body_util::BodyExt::collect(body));
+            let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+            let std::task::Poll::Ready(Ok(collected)) = collect.as_mut().poll(&mut cx) else {
+                panic!("body is not ready");
+            };
+            assert_eq!(collected.to_bytes(), input.as_bytes(), "{codec:?}");
+        }
+    }
+
+    #[test]
+    fn test_precompress_dir() {
+        let dir = test_dir();
+        fs::create_dir(dir.join("nested")).unwrap();
+        fs::write(dir.join("app.js"), "console.log('hi');\n".repeat(100)).unwrap();
+        fs::write(
+            dir.join("nested/style.css"),
+            "body { color: red; }\n".repeat(100),
+        )
+        .unwrap();
+        fs::write(dir.join("small.txt"), "tiny").unwrap();
+        fs::write(dir.join("photo.png"), "not really a png ".repeat(100)).unwrap();
+        fs::write(dir.join("noise.bin"), noise(4096)).unwrap();
+
+        let written = precompress_dir(&dir, &CompressionLayer::new()).unwrap();
+
+        let codecs: Vec<_> = variant_codecs().iter().collect();
+        assert_eq!(written.len(), codecs.len() * 2);
+        for codec in codecs {
+            let extension = codec.file_extension().unwrap();
+            assert!(dir.join(format!("app.js.{extension}")).exists());
+            assert!(dir.join(format!("nested/style.css.{extension}")).exists());
+            assert!(!dir.join(format!("small.txt.{extension}")).exists());
+            assert!(!dir.join(format!("photo.png.{extension}")).exists());
+            assert!(!dir.join(format!("noise.bin.{extension}")).exists());
+        }
+
+        // Running again doesn't compress the variants themselves
+        let rewritten = precompress_dir(&dir, &CompressionLayer::new()).unwrap();
+        assert_eq!(rewritten, written);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_removes_stale_variant() {
+        let dir = test_dir();
+        fs::write(dir.join("noise.bin"), noise(4096)).unwrap();
+        fs::write(dir.join("noise.bin.gz"), "stale").unwrap();
+
+        let layer = CompressionLayer::new().min_size(0);
+        assert!(precompress_dir(&dir, &layer).unwrap().is_empty());
+        assert!(!dir.join("noise.bin.gz").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_removes_variants_of_skipped_files() {
+        let dir = test_dir();
+        fs::write(dir.join("small.txt"), "tiny").unwrap();
+        fs::write(dir.join("small.txt.gz"), "stale").unwrap();
+        fs::write(dir.join("photo.png"), "not really a png ".repeat(100)).unwrap();
+        fs::write(dir.join("photo.png.gz"), "stale").unwrap();
+
+        assert!(
+            precompress_dir(&dir, &CompressionLayer::new())
+                .unwrap()
+                .is_empty()
+        );
+        assert!(dir.join("small.txt").exists());
+        assert!(!dir.join("small.txt.gz").exists());
+        assert!(dir.join("photo.png").exists());
+        assert!(!dir.join("photo.png.gz").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(all(feature = "zstd", feature = "gzip"))]
+    fn test_respects_disabled_codecs() {
+        let dir = test_dir();
+        fs::write(dir.join("app.js"), "console.log('hi');\n".repeat(100)).unwrap();
+
+        let layer = CompressionLayer::new().disable(Codec::Zstd);
+        precompress_dir(&dir, &layer).unwrap();
+        assert!(dir.join("app.js.gz").exists());
+        assert!(!dir.join("app.js.zst").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
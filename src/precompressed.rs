@@ -0,0 +1,465 @@
+use crate::codec::{Codec, CodecPreference};
+use crate::future::add_vary_accept_encoding;
+use http::{HeaderValue, Method, Request, Response, StatusCode, Uri, header, request};
+use pin_project_lite::pin_project;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tower::{Layer, Service};
+
+/// Content types of common static assets, by file extension.
+const CONTENT_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("xml", "application/xml"),
+    ("svg", "image/svg+xml"),
+    ("wasm", "application/wasm"),
+    ("ico", "image/x-icon"),
+    ("webmanifest", "application/manifest+json"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("mp3", "audio/mpeg"),
+    ("zip", "application/zip"),
+];
+
+/// A Tower layer that serves precompressed variants of static assets.
+///
+/// For `GET` and `HEAD` requests, the layer negotiates a codec with the
+/// client's `Accept-Encoding` and asks the inner service for the sibling
+/// variant instead, such as `/app.js.br` for `/app.js`. If the variant exists,
+/// it is returned with `Content-Encoding` set and its `Content-Length` and
+/// `Accept-Ranges` intact; if the inner service responds with `404 Not Found`,
+/// the original path is requested instead.
+///
+/// The variant's `Content-Type` is replaced based on the original path's
+/// extension for common web assets, or removed if it is unknown. Codecs
+/// without a file extension (deflate) are never used.
+///
+/// Requests for directories (paths ending in `/`) are passed through
+/// unchanged.
+#[derive(Debug, Clone)]
+pub struct PrecompressedLayer {
+    codecs: CodecPreference,
+}
+
+impl PrecompressedLayer {
+    /// Creates a new layer serving variants for every enabled codec.
+    pub fn new() -> Self {
+        Self {
+            codecs: variant_codecs(),
+        }
+    }
+
+    /// Disables a codec, so its variants are never served.
+    pub fn disable(mut self, codec: Codec) -> Self {
+        self.codecs.disable(codec);
+        self
+    }
+}
+
+impl Default for PrecompressedLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> Layer<S> for PrecompressedLayer {
+    type Service = PrecompressedService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        PrecompressedService {
+            inner,
+            codecs: self.codecs.clone(),
+        }
+    }
+}
+
+/// Returns the enabled codecs that have a file extension.
+pub(crate) fn variant_codecs() -> CodecPreference {
+    let mut codecs = CodecPreference::default();
+    for codec in CodecPreference::default().iter() {
+        if codec.file_extension().is_none() {
+            codecs.disable(codec);
+        }
+    }
+    codecs
+}
+
+/// A Tower service that serves precompressed variants of static assets.
+///
+/// See [`PrecompressedLayer`] for details.
+#[derive(Debug, Clone)]
+pub struct PrecompressedService<S> {
+    inner: S,
+    codecs: CodecPreference,
+}
+
+impl<S> PrecompressedService<S> {
+    /// Creates a new service serving variants for every enabled codec.
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            codecs: variant_codecs(),
+        }
+    }
+
+    /// Returns a reference to the inner service.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner service.
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Consumes this service, returning the inner service.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for PrecompressedService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone,
+    ReqBody: Default,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = PrecompressedFuture<S, ReqBody>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let is_asset = matches!(*req.method(), Method::GET | Method::HEAD);
+        let variant = is_asset
+            .then(|| self.codecs.negotiate_headers(req.headers()))
+            .flatten()
+            .and_then(|codec| Some((codec, variant_uri(req.uri(), codec)?)));
+
+        let Some((codec, uri)) = variant else {
+            return PrecompressedFuture {
+                state: State::Original {
+                    future: self.inner.call(req),
+                    vary: is_asset,
+                },
+            };
+        };
+
+        // Call the ready service, leaving a clone in its place; the called
+        // service is kept to request the original path if the variant is missing
+        let fallback = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, fallback);
+
+        let (parts, body) = req.into_parts();
+        let original = parts.clone();
+        let mut variant = Request::from_parts(parts, body);
+        *variant.uri_mut() = uri;
+
+        PrecompressedFuture {
+            state: State::Variant {
+                future: inner.call(variant),
+                codec,
+                fallback: Some((inner, original)),
+            },
+        }
+    }
+}
+
+/// Returns the URI of the codec's variant, or `None` for directories.
+fn variant_uri(uri: &Uri, codec: Codec) -> Option<Uri> {
+    let extension = codec.file_extension()?;
+    let path = uri.path();
+    if path.ends_with('/') {
+        return None;
+    }
+
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{path}.{extension}?{query}"),
+        None => format!("{path}.{extension}"),
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().ok()?);
+    Uri::from_parts(parts).ok()
+}
+
+/// Returns the content type of a path, based on its extension.
+pub(crate) fn content_type(path: &str) -> Option<&'static str> {
+    let name = path.rsplit('/').next()?;
+    let (_, extension) = name.rsplit_once('.')?;
+    CONTENT_TYPES
+        .iter()
+        .find(|(ext, _)| ext.eq_ignore_ascii_case(extension))
+        .map(|(_, content_type)| *content_type)
+}
+
+pin_project! {
+    /// Future for precompressed service responses.
+    pub struct PrecompressedFuture<S, B>
+    where
+        S: Service<Request<B>>,
+    {
+        #[pin]
+        state: State<S, B>,
+    }
+}
+
+pin_project! {
+    #[project = StateProj]
+    enum State<S, B>
+    where
+        S: Service<Request<B>>,
+    {
+        // Waiting for the variant, with the service and request parts to
+        // retry with if it doesn't exist
+        Variant {
+            #[pin]
+            future: S::Future,
+            codec: Codec,
+            fallback: Option<(S, request::Parts)>,
+        },
+        // Waiting for the fallback service to become ready
+        Fallback {
+            service: S,
+            parts: Option<request::Parts>,
+        },
+        // Waiting for the original path
+        Original {
+            #[pin]
+            future: S::Future,
+            vary: bool,
+        },
+    }
+}
+
+impl<S, B, ResBody> Future for PrecompressedFuture<S, B>
+where
+    S: Service<Request<B>, Response = Response<ResBody>>,
+    B: Default,
+{
+    type Output = Result<Response<ResBody>, S::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.project().state;
+        loop {
+            match state.as_mut().project() {
+                StateProj::Variant {
+                    future,
+                    codec,
+                    fallback,
+                } => {
+                    let mut response = ready!(future.poll(cx))?;
+                    if response.status() == StatusCode::NOT_FOUND {
+                        let (service, parts) = fallback.take().expect("polled after completion");
+                        state.set(State::Fallback {
+                            service,
+                            parts: Some(parts),
+                        });
+                        continue;
+                    }
+
+                    let (_, parts) = fallback.as_ref().expect("polled after completion");
+                    if response.status().is_success()
+                        || response.status() == StatusCode::NOT_MODIFIED
+                    {
+                        mark_variant(&mut response, *codec, parts.uri.path());
+                    }
+                    add_vary_accept_encoding(response.headers_mut());
+                    return Poll::Ready(Ok(response));
+                }
+                StateProj::Fallback { service, parts } => {
+                    ready!(service.poll_ready(cx))?;
+                    let parts = parts.take().expect("polled after completion");
+                    let future = service.call(Request::from_parts(parts, B::default()));
+                    state.set(State::Original { future, vary: true });
+                }
+                StateProj::Original { future, vary } => {
+                    let mut response = ready!(future.poll(cx))?;
+                    if *vary {
+                        add_vary_accept_encoding(response.headers_mut());
+                    }
+                    return Poll::Ready(Ok(response));
+                }
+            }
+        }
+    }
+}
+
+/// Sets the headers of a successful variant response.
+fn mark_variant<B>(response: &mut Response<B>, codec: Codec, original_path: &str) {
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(codec.content_encoding()),
+    );
+    match content_type(original_path) {
+        Some(content_type) => {
+            headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+        }
+        None => {
+            headers.remove(header::CONTENT_TYPE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use tower::ServiceExt;
+
+    fn block_on<T>(future: impl Future<Output = T>) -> T {
+        let mut future = std::pin::pin!(future);
+        let mut cx = Context::from_waker(std::task::Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    /// A static file server with the given files, which serves them as
+    /// `application/octet-stream` with ranges enabled.
+    fn files(
+        files: &'static [(&'static str, &'static str)],
+    ) -> impl Service<
+        Request<()>,
+        Response = Response<String>,
+        Error = Infallible,
+        Future = impl Future<Output = Result<Response<String>, Infallible>>,
+    > + Clone {
+        tower::service_fn(move |req: Request<()>| async move {
+            let Some((_, contents)) = files.iter().find(|(path, _)| *path == req.uri().path())
+            else {
+                let mut response = Response::new(String::new());
+                *response.status_mut() = StatusCode::NOT_FOUND;
+                return Ok(response);
+            };
+            let response = Response::builder()
+                .header(header::CONTENT_TYPE, "application/octet-stream")
+                .header(header::CONTENT_LENGTH, contents.len())
+                .header(header::ACCEPT_RANGES, "bytes")
+                .body(contents.to_string())
+                .unwrap();
+            Ok(response)
+        })
+    }
+
+    fn get(path: &str, accept_encoding: &str) -> Request<()> {
+        Request::get(path)
+            .header(header::ACCEPT_ENCODING, accept_encoding)
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_variant_uri() {
+        for codec in variant_codecs().iter() {
+            let extension = codec.file_extension().unwrap();
+            let uri = variant_uri(&"/app.js?v=1".parse().unwrap(), codec).unwrap();
+            assert_eq!(uri, format!("/app.js.{extension}?v=1").as_str());
+            assert!(variant_uri(&"/assets/".parse().unwrap(), codec).is_none());
+        }
+    }
+
+    #[test]
+    fn test_content_type() {
+        assert_eq!(
+            content_type("/app.JS"),
+            Some("text/javascript; charset=utf-8")
+        );
+        assert_eq!(
+            content_type("/index.html"),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(content_type("/dir.css/file"), None);
+        assert_eq!(content_type("/unknown.xyz"), None);
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_serves_variant() {
+        let service = PrecompressedLayer::new()
+            .layer(files(&[("/app.js", "plain"), ("/app.js.gz", "gzipped")]));
+        let response = block_on(service.oneshot(get("/app.js", "gzip"))).unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), "gzipped");
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "7");
+        assert_eq!(response.headers()[header::ACCEPT_RANGES], "bytes");
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/javascript; charset=utf-8"
+        );
+        assert_eq!(response.headers()[header::VARY], "accept-encoding");
+    }
+
+    #[test]
+    #[cfg(all(feature = "zstd", feature = "gzip"))]
+    fn test_prefers_negotiated_codec() {
+        let service = PrecompressedLayer::new()
+            .layer(files(&[("/app.js.gz", "gzipped"), ("/app.js.zst", "zstd")]));
+        let response = block_on(service.oneshot(get("/app.js", "gzip, zstd"))).unwrap();
+        assert_eq!(response.body(), "zstd");
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "zstd");
+
+        let service = PrecompressedLayer::new()
+            .disable(Codec::Zstd)
+            .layer(files(&[("/app.js.gz", "gzipped"), ("/app.js.zst", "zstd")]));
+        let response = block_on(service.oneshot(get("/app.js", "gzip, zstd"))).unwrap();
+        assert_eq!(response.body(), "gzipped");
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_falls_back_to_original() {
+        let service = PrecompressedLayer::new().layer(files(&[("/app.js", "plain")]));
+        let response = block_on(service.clone().oneshot(get("/app.js", "gzip"))).unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), "plain");
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/octet-stream"
+        );
+        assert_eq!(response.headers()[header::VARY], "accept-encoding");
+
+        let response = block_on(service.oneshot(get("/missing.js", "gzip"))).unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_identity_and_other_methods_pass_through() {
+        let service = PrecompressedLayer::new().layer(files(&[("/app.js", "plain")]));
+        let response = block_on(service.clone().oneshot(get("/app.js", "identity"))).unwrap();
+        assert_eq!(response.body(), "plain");
+        assert_eq!(response.headers()[header::VARY], "accept-encoding");
+
+        let req = Request::post("/app.js")
+            .header(header::ACCEPT_ENCODING, "gzip, zstd, br")
+            .body(())
+            .unwrap();
+        let response = block_on(service.oneshot(req)).unwrap();
+        assert_eq!(response.body(), "plain");
+        assert!(response.headers().get(header::VARY).is_none());
+    }
+}
@@ -0,0 +1,428 @@
+use crate::breach::NoCompression;
+use crate::content_type::ContentTypeFilter;
+use http::{Extensions, HeaderMap, Method, Request, StatusCode, Uri, Version, header, response};
+use http_body::SizeHint;
+
+/// Decides whether a response should be compressed.
+///
+/// Predicates are evaluated after the built-in rules, so they can only prevent
+/// compression, never force it. Closures taking a [`RequestInfo`] and a
+/// [`ResponseInfo`] implement this trait.
+///
+/// ```ignore
+/// use http_response_compression::{CompressionLayer, Predicate, RequestInfo, ResponseInfo};
+///
+/// let layer = CompressionLayer::new().compress_when(
+///     (|req: &RequestInfo, _: &ResponseInfo<'_>| !req.uri().path().starts_with("/health"))
+///         .and(|_: &RequestInfo, res: &ResponseInfo<'_>| res.status().is_success()),
+/// );
+/// ```
+pub trait Predicate: Send + Sync + 'static {
+    /// Returns whether the response should be compressed.
+    fn should_compress(&self, request: &RequestInfo, response: &ResponseInfo<'_>) -> bool;
+
+    /// Combines two predicates, compressing only when both return true.
+    fn and<P>(self, other: P) -> And<Self, P>
+    where
+        Self: Sized,
+        P: Predicate,
+    {
+        And(self, other)
+    }
+
+    /// Combines two predicates, compressing when either returns true.
+    fn or<P>(self, other: P) -> Or<Self, P>
+    where
+        Self: Sized,
+        P: Predicate,
+    {
+        Or(self, other)
+    }
+
+    /// Inverts this predicate.
+    fn not(self) -> Not<Self>
+    where
+        Self: Sized,
+    {
+        Not(self)
+    }
+}
+
+impl<F> Predicate for F
+where
+    F: Fn(&RequestInfo, &ResponseInfo<'_>) -> bool + Send + Sync + 'static,
+{
+    fn should_compress(&self, request: &RequestInfo, response: &ResponseInfo<'_>) -> bool {
+        self(request, response)
+    }
+}
+
+/// Request information available to predicates.
+///
+/// This is captured when the request passes through the middleware, before it
+/// is handed to the inner service.
+#[derive(Debug, Clone, Default)]
+pub struct RequestInfo {
+    method: Method,
+    uri: Uri,
+    version: Version,
+    headers: HeaderMap,
+}
+
+impl RequestInfo {
+    /// Captures the information of a request.
+    pub fn from_request<B>(request: &Request<B>) -> Self {
+        Self {
+            method: request.method().clone(),
+            uri: request.uri().clone(),
+            version: request.version(),
+            headers: request.headers().clone(),
+        }
+    }
+
+    /// Returns the request method.
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    /// Returns the request URI.
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+
+    /// Returns the request HTTP version.
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Returns the request headers.
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+}
+
+/// Response information available to predicates.
+#[derive(Debug, Clone)]
+pub struct ResponseInfo<'a> {
+    parts: &'a response::Parts,
+    size_hint: SizeHint,
+}
+
+impl<'a> ResponseInfo<'a> {
+    /// Creates response information from the response parts and the body's
+    /// size hint.
+    pub fn new(parts: &'a response::Parts, size_hint: SizeHint) -> Self {
+        Self { parts, size_hint }
+    }
+
+    /// Returns the response status.
+    pub fn status(&self) -> StatusCode {
+        self.parts.status
+    }
+
+    /// Returns the response HTTP version.
+    pub fn version(&self) -> Version {
+        self.parts.version
+    }
+
+    /// Returns the response headers.
+    pub fn headers(&self) -> &'a HeaderMap {
+        &self.parts.headers
+    }
+
+    /// Returns the response extensions.
+    pub fn extensions(&self) -> &'a Extensions {
+        &self.parts.extensions
+    }
+
+    /// Returns the size hint reported by the response body.
+    pub fn size_hint(&self) -> &SizeHint {
+        &self.size_hint
+    }
+
+    /// Returns an upper bound on the body length, if known.
+    ///
+    /// The `Content-Length` header is used when present, otherwise the body's
+    /// size hint.
+    pub fn body_len_upper_bound(&self) -> Option<u64> {
+        self.headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .or_else(|| self.size_hint.upper())
+    }
+}
+
+/// Predicate that compresses only when both inner predicates do.
+///
+/// Created by [`Predicate::and`].
+#[derive(Debug, Clone, Copy)]
+pub struct And<A, B>(A, B);
+
+impl<A: Predicate, B: Predicate> Predicate for And<A, B> {
+    fn should_compress(&self, request: &RequestInfo, response: &ResponseInfo<'_>) -> bool {
+        self.0.should_compress(request, response) && self.1.should_compress(request, response)
+    }
+}
+
+/// Predicate that compresses when either inner predicate does.
+///
+/// Created by [`Predicate::or`].
+#[derive(Debug, Clone, Copy)]
+pub struct Or<A, B>(A, B);
+
+impl<A: Predicate, B: Predicate> Predicate for Or<A, B> {
+    fn should_compress(&self, request: &RequestInfo, response: &ResponseInfo<'_>) -> bool {
+        self.0.should_compress(request, response) || self.1.should_compress(request, response)
+    }
+}
+
+/// Predicate that inverts its inner predicate.
+///
+/// Created by [`Predicate::not`].
+#[derive(Debug, Clone, Copy)]
+pub struct Not<P>(P);
+
+impl<P: Predicate> Predicate for Not<P> {
+    fn should_compress(&self, request: &RequestInfo, response: &ResponseInfo<'_>) -> bool {
+        !self.0.should_compress(request, response)
+    }
+}
+
+/// Compresses only responses without a `Content-Encoding` header.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoContentEncoding;
+
+impl Predicate for NoContentEncoding {
+    fn should_compress(&self, _: &RequestInfo, response: &ResponseInfo<'_>) -> bool {
+        !has_content_encoding(response.headers())
+    }
+}
+
+/// Compresses only responses without a `Content-Range` header.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoContentRange;
+
+impl Predicate for NoContentRange {
+    fn should_compress(&self, _: &RequestInfo, response: &ResponseInfo<'_>) -> bool {
+        !has_content_range(response.headers())
+    }
+}
+
+/// Skips responses with `Cache-Control: no-transform`, which intermediaries
+/// must not modify.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoTransform;
+
+impl Predicate for NoTransform {
+    fn should_compress(&self, _: &RequestInfo, response: &ResponseInfo<'_>) -> bool {
+        !has_no_transform(response.headers())
+    }
+}
+
+/// Skips responses whose body is known to be below a minimum size.
+///
+/// The length comes from the `Content-Length` header, or else from the body's
+/// size hint. Responses of unknown length are compressed.
+#[derive(Debug, Clone, Copy)]
+pub struct MinSize(usize);
+
+impl MinSize {
+    /// Creates a predicate that skips responses smaller than `min_size` bytes.
+    pub fn new(min_size: usize) -> Self {
+        Self(min_size)
+    }
+
+    /// Returns the minimum size in bytes.
+    pub fn get(&self) -> usize {
+        self.0
+    }
+}
+
+impl Predicate for MinSize {
+    fn should_compress(&self, _: &RequestInfo, response: &ResponseInfo<'_>) -> bool {
+        response
+            .body_len_upper_bound()
+            .is_none_or(|len| len >= self.0 as u64)
+    }
+}
+
+/// The built-in rules, which are always applied before any custom predicate.
+#[derive(Debug, Clone)]
+pub(crate) struct DefaultPredicate {
+    pub(crate) min_size: MinSize,
+    pub(crate) content_types: ContentTypeFilter,
+    pub(crate) respect_no_transform: bool,
+}
+
+impl DefaultPredicate {
+    /// Creates the built-in rules with the given minimum size.
+    pub(crate) fn new(min_size: usize) -> Self {
+        Self {
+            min_size: MinSize::new(min_size),
+            content_types: ContentTypeFilter::default(),
+            respect_no_transform: true,
+        }
+    }
+}
+
+impl DefaultPredicate {
+    /// Applies the rules with another minimum size.
+    ///
+    /// Returns the rule that rejected the response, if any.
+    pub(crate) fn check_with(
+        &self,
+        request: &RequestInfo,
+        response: &ResponseInfo<'_>,
+        min_size: MinSize,
+    ) -> Result<(), &'static str> {
+        if !NoContentEncoding.should_compress(request, response) {
+            Err("content-encoding present")
+        } else if !NoContentRange.should_compress(request, response) {
+            Err("content-range present")
+        } else if response.extensions().get::<NoCompression>().is_some() {
+            Err("no compression extension")
+        } else if self.respect_no_transform && !NoTransform.should_compress(request, response) {
+            Err("cache-control no-transform")
+        } else if !self.content_types.should_compress(request, response) {
+            Err("content type not compressible")
+        } else if !min_size.should_compress(request, response) {
+            Err("body below minimum size")
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Predicate for DefaultPredicate {
+    fn should_compress(&self, request: &RequestInfo, response: &ResponseInfo<'_>) -> bool {
+        self.check_with(request, response, self.min_size).is_ok()
+    }
+}
+
+/// Checks if Content-Encoding header is already present.
+fn has_content_encoding(headers: &HeaderMap) -> bool {
+    headers.contains_key(header::CONTENT_ENCODING)
+}
+
+/// Checks if Content-Range header is present (range response).
+fn has_content_range(headers: &HeaderMap) -> bool {
+    headers.contains_key(header::CONTENT_RANGE)
+}
+
+/// Checks if any Cache-Control header contains the no-transform directive.
+fn has_no_transform(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::Response;
+
+    fn parts_with_headers<I>(headers: I) -> response::Parts
+    where
+        I: IntoIterator<Item = (&'static str, &'static str)>,
+    {
+        let mut response = Response::new(());
+        for (name, value) in headers {
+            response
+                .headers_mut()
+                .insert(name, header::HeaderValue::from_static(value));
+        }
+        response.into_parts().0
+    }
+
+    fn check<P: Predicate>(predicate: P, parts: &response::Parts) -> bool {
+        predicate.should_compress(
+            &RequestInfo::default(),
+            &ResponseInfo::new(parts, SizeHint::default()),
+        )
+    }
+
+    #[test]
+    fn test_builtin_predicates() {
+        let plain = parts_with_headers([]);
+        assert!(check(NoContentEncoding, &plain));
+        assert!(check(NoContentRange, &plain));
+        assert!(check(ContentTypeFilter::default(), &plain));
+        assert!(check(NoTransform, &plain));
+        assert!(check(MinSize::new(100), &plain));
+
+        let encoded = parts_with_headers([("content-encoding", "br")]);
+        assert!(!check(NoContentEncoding, &encoded));
+
+        let range = parts_with_headers([("content-range", "bytes 0-9/100")]);
+        assert!(!check(NoContentRange, &range));
+
+        let image = parts_with_headers([("content-type", "image/png")]);
+        assert!(!check(ContentTypeFilter::default(), &image));
+
+        let no_transform = parts_with_headers([("cache-control", "public, No-Transform")]);
+        assert!(!check(NoTransform, &no_transform));
+        let transform = parts_with_headers([("cache-control", "no-cache, max-age=60")]);
+        assert!(check(NoTransform, &transform));
+
+        let small = parts_with_headers([("content-length", "10")]);
+        assert!(!check(MinSize::new(100), &small));
+        assert!(check(MinSize::new(10), &small));
+    }
+
+    #[test]
+    fn test_min_size_uses_size_hint() {
+        let check_hint = |hint: SizeHint, parts: &response::Parts| {
+            MinSize::new(100)
+                .should_compress(&RequestInfo::default(), &ResponseInfo::new(parts, hint))
+        };
+        let plain = parts_with_headers([]);
+
+        assert!(!check_hint(SizeHint::with_exact(10), &plain));
+        assert!(check_hint(SizeHint::with_exact(100), &plain));
+
+        let mut bounded = SizeHint::new();
+        bounded.set_upper(50);
+        assert!(!check_hint(bounded, &plain));
+
+        let mut unbounded = SizeHint::new();
+        unbounded.set_lower(10);
+        assert!(check_hint(unbounded, &plain));
+
+        // Content-Length takes precedence over the size hint
+        let large = parts_with_headers([("content-length", "1000")]);
+        assert!(check_hint(SizeHint::with_exact(10), &large));
+    }
+
+    #[test]
+    fn test_combinators() {
+        let parts = parts_with_headers([("content-type", "text/html")]);
+        let yes = |_: &RequestInfo, _: &ResponseInfo<'_>| true;
+        let no = |_: &RequestInfo, _: &ResponseInfo<'_>| false;
+
+        assert!(check(yes.and(yes), &parts));
+        assert!(!check(yes.and(no), &parts));
+        assert!(check(no.or(yes), &parts));
+        assert!(!check(no.or(no), &parts));
+        assert!(check(no.not(), &parts));
+        assert!(!check(yes.not(), &parts));
+    }
+
+    #[test]
+    fn test_request_info() {
+        let request = Request::head("/health")
+            .header("x-internal", "1")
+            .body(())
+            .unwrap();
+        let info = RequestInfo::from_request(&request);
+
+        let is_health = |req: &RequestInfo, _: &ResponseInfo<'_>| req.uri().path() == "/health";
+        let parts = parts_with_headers([]);
+        assert!(is_health.should_compress(&info, &ResponseInfo::new(&parts, SizeHint::default())));
+        assert_eq!(info.method(), Method::HEAD);
+        assert!(info.headers().contains_key("x-internal"));
+    }
+}
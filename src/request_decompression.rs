@@ -0,0 +1,345 @@
+use crate::codec::{Codec, CodecPreference};
+use crate::decompression_body::{DecompressionBody, DecompressionLimits, content_coding};
+use http::{Request, Response, StatusCode, header};
+use pin_project_lite::pin_project;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+
+/// A Tower layer that decompresses HTTP request bodies.
+///
+/// Requests with a supported `Content-Encoding` have their body decoded and
+/// the `Content-Encoding` and `Content-Length` headers removed before they
+/// reach the inner service. Requests with an unsupported encoding are
+/// rejected with `415 Unsupported Media Type` and an `Accept-Encoding`
+/// header listing the supported codecs.
+#[derive(Debug, Clone, Default)]
+pub struct RequestDecompressionLayer {
+    codecs: CodecPreference,
+    limits: DecompressionLimits,
+}
+
+impl RequestDecompressionLayer {
+    /// Creates a new request decompression layer accepting every enabled codec.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Disables a codec, so requests encoded with it are rejected.
+    pub fn disable(mut self, codec: Codec) -> Self {
+        self.codecs.disable(codec);
+        self
+    }
+
+    /// Sets the maximum size of a decompressed body, in bytes.
+    ///
+    /// Reading further fails with [`DecompressionError::SizeLimitExceeded`].
+    /// There is no limit by default.
+    ///
+    /// [`DecompressionError::SizeLimitExceeded`]: crate::DecompressionError::SizeLimitExceeded
+    pub fn max_decompressed_size(mut self, bytes: u64) -> Self {
+        self.limits.max_size = Some(bytes);
+        self
+    }
+
+    /// Sets the maximum ratio of decompressed to compressed size.
+    ///
+    /// Reading further fails with [`DecompressionError::RatioLimitExceeded`].
+    /// The ratio is only enforced once more than 64 KiB has been decompressed,
+    /// so small but highly compressible bodies are accepted. There is no limit
+    /// by default.
+    ///
+    /// [`DecompressionError::RatioLimitExceeded`]: crate::DecompressionError::RatioLimitExceeded
+    pub fn max_expansion_ratio(mut self, ratio: u64) -> Self {
+        self.limits.max_ratio = Some(ratio);
+        self
+    }
+}
+
+impl<S> Layer<S> for RequestDecompressionLayer {
+    type Service = RequestDecompressionService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestDecompressionService {
+            inner,
+            codecs: self.codecs.clone(),
+            limits: self.limits,
+        }
+    }
+}
+
+/// A Tower service that decompresses HTTP request bodies.
+///
+/// See [`RequestDecompressionLayer`] for details.
+#[derive(Debug, Clone)]
+pub struct RequestDecompressionService<S> {
+    inner: S,
+    codecs: CodecPreference,
+    limits: DecompressionLimits,
+}
+
+impl<S> RequestDecompressionService<S> {
+    /// Creates a new request decompression service accepting every enabled codec.
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            codecs: CodecPreference::default(),
+            limits: DecompressionLimits::default(),
+        }
+    }
+
+    /// Returns a reference to the inner service.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner service.
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Consumes this service, returning the inner service.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for RequestDecompressionService<S>
+where
+    S: Service<Request<DecompressionBody<ReqBody>>, Response = Response<ResBody>>,
+    ResBody: Default,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = RequestDecompressionFuture<S::Future, ResBody>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let (mut parts, body) = req.into_parts();
+
+        let body = match content_coding(&parts.headers, &self.codecs) {
+            Ok(None) => DecompressionBody::passthrough(body),
+            Ok(Some(codec)) => {
+                parts.headers.remove(header::CONTENT_LENGTH);
+                DecompressionBody::with_limits(body, codec, self.limits)
+            }
+            Err(()) => {
+                let mut response = Response::new(ResBody::default());
+                *response.status_mut() = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+                if let Some(accept) = self.codecs.accept_encoding() {
+                    response
+                        .headers_mut()
+                        .insert(header::ACCEPT_ENCODING, accept);
+                }
+                return RequestDecompressionFuture::rejected(response);
+            }
+        };
+        parts.headers.remove(header::CONTENT_ENCODING);
+
+        let inner = self.inner.call(Request::from_parts(parts, body));
+        RequestDecompressionFuture::inner(inner)
+    }
+}
+
+pin_project! {
+    /// Future for request decompression service responses.
+    pub struct RequestDecompressionFuture<F, B> {
+        #[pin]
+        kind: Kind<F, B>,
+    }
+}
+
+pin_project! {
+    #[project = KindProj]
+    enum Kind<F, B> {
+        Inner {
+            #[pin]
+            future: F,
+        },
+        Rejected {
+            response: Option<Response<B>>,
+        },
+    }
+}
+
+impl<F, B> RequestDecompressionFuture<F, B> {
+    fn inner(future: F) -> Self {
+        Self {
+            kind: Kind::Inner { future },
+        }
+    }
+
+    fn rejected(response: Response<B>) -> Self {
+        Self {
+            kind: Kind::Rejected {
+                response: Some(response),
+            },
+        }
+    }
+}
+
+impl<F, B, E> Future for RequestDecompressionFuture<F, B>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+{
+    type Output = Result<Response<B>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project().kind.project() {
+            KindProj::Inner { future } => future.poll(cx),
+            KindProj::Rejected { response } => {
+                Poll::Ready(Ok(response.take().expect("polled after completion")))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use http_body_util::{BodyExt, Full};
+    use std::convert::Infallible;
+    use tower::ServiceExt;
+
+    /// A service that responds with the request body and its headers.
+    fn echo() -> impl Service<
+        Request<Full<Bytes>>,
+        Response = Response<String>,
+        Error = Infallible,
+        Future = impl Future<Output = Result<Response<String>, Infallible>>,
+    > {
+        RequestDecompressionLayer::new().layer(tower::service_fn(
+            |req: Request<DecompressionBody<Full<Bytes>>>| async move {
+                let (parts, body) = req.into_parts();
+                let body = body.collect().await.unwrap().to_bytes();
+                let mut response = Response::new(String::from_utf8(body.to_vec()).unwrap());
+                *response.headers_mut() = parts.headers;
+                Ok(response)
+            },
+        ))
+    }
+
+    fn block_on<T>(future: impl Future<Output = T>) -> T {
+        let mut future = std::pin::pin!(future);
+        let mut cx = Context::from_waker(std::task::Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    fn request(content_encoding: Option<&'static str>, body: Bytes) -> Request<Full<Bytes>> {
+        let mut builder = Request::post("/").header(header::CONTENT_LENGTH, body.len());
+        if let Some(content_encoding) = content_encoding {
+            builder = builder.header(header::CONTENT_ENCODING, content_encoding);
+        }
+        builder.body(Full::new(body)).unwrap()
+    }
+
+    #[test]
+    fn test_uncompressed_request() {
+        let response = block_on(echo().oneshot(request(None, Bytes::from("hello")))).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), "hello");
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "5");
+    }
+
+    #[test]
+    fn test_identity_request() {
+        let req = request(Some("identity"), Bytes::from("hello"));
+        let response = block_on(echo().oneshot(req)).unwrap();
+        assert_eq!(response.body(), "hello");
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+    }
+
+    #[test]
+    fn test_compressed_request() {
+        for codec in CodecPreference::default().iter() {
+            let body = crate::body::CompressionBody::compressed(
+                Full::new(Bytes::from("{\"hello\":\"world\"}")),
+                codec,
+                false,
+            );
+            let compressed = block_on(body.collect()).unwrap().to_bytes();
+
+            let req = request(Some(codec.content_encoding()), compressed);
+            let response = block_on(echo().oneshot(req)).unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.body(), "{\"hello\":\"world\"}", "{codec:?}");
+            assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+            assert!(response.headers().get(header::CONTENT_LENGTH).is_none());
+        }
+    }
+
+    #[test]
+    fn test_unsupported_encoding() {
+        for content_encoding in ["compress", "gzip, gzip", "gzip, unknown"] {
+            let req = request(Some(content_encoding), Bytes::from("hello"));
+            let response = block_on(echo().oneshot(req)).unwrap();
+            assert_eq!(
+                response.status(),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "{content_encoding}"
+            );
+            assert_eq!(
+                response.headers()[header::ACCEPT_ENCODING],
+                CodecPreference::default().accept_encoding().unwrap()
+            );
+            assert!(response.body().is_empty());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_disabled_codec_is_rejected() {
+        let service =
+            RequestDecompressionLayer::new()
+                .disable(Codec::Gzip)
+                .layer(tower::service_fn(
+                    |_: Request<DecompressionBody<Full<Bytes>>>| async {
+                        Ok::<_, Infallible>(Response::new(String::new()))
+                    },
+                ));
+        let req = request(Some("gzip"), Bytes::from("hello"));
+        let response = block_on(service.oneshot(req)).unwrap();
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert!(
+            !response.headers()[header::ACCEPT_ENCODING]
+                .to_str()
+                .unwrap()
+                .contains("gzip")
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_limits_apply_to_request_body() {
+        let service = RequestDecompressionLayer::new()
+            .max_decompressed_size(1024)
+            .layer(tower::service_fn(
+                |req: Request<DecompressionBody<Full<Bytes>>>| async move {
+                    let err = req.into_body().collect().await.unwrap_err();
+                    assert!(matches!(
+                        err,
+                        crate::DecompressionError::SizeLimitExceeded { limit: 1024 }
+                    ));
+                    Ok::<_, Infallible>(Response::new(String::new()))
+                },
+            ));
+
+        let body = crate::body::CompressionBody::compressed(
+            Full::new(Bytes::from(vec![b'a'; 100_000])),
+            Codec::Gzip,
+            false,
+        );
+        let compressed = block_on(body.collect()).unwrap().to_bytes();
+        let response = block_on(service.oneshot(request(Some("gzip"), compressed))).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -1,6 +1,11 @@
-use crate::codec::Codec;
+use crate::accept_encoding::AcceptEncoding;
+use crate::dictionary;
 use crate::future::ResponseFuture;
+use crate::overrides::CompressionPolicy;
+use crate::predicate::RequestInfo;
+use crate::settings::Settings;
 use http::Request;
+use std::sync::Arc;
 use std::task::{Context, Poll};
 use tower::Service;
 
@@ -8,13 +13,18 @@ use tower::Service;
 #[derive(Debug, Clone)]
 pub struct CompressionService<S> {
     inner: S,
-    min_size: usize,
+    settings: Arc<Settings>,
 }
 
 impl<S> CompressionService<S> {
     /// Creates a new compression service wrapping the given inner service.
     pub fn new(inner: S, min_size: usize) -> Self {
-        Self { inner, min_size }
+        Self::with_settings(inner, Arc::new(Settings::new(min_size)))
+    }
+
+    /// Creates a new compression service with the given settings.
+    pub(crate) fn with_settings(inner: S, settings: Arc<Settings>) -> Self {
+        Self { inner, settings }
     }
 
     /// Returns a reference to the inner service.
@@ -36,6 +46,9 @@ impl<S> CompressionService<S> {
 impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for CompressionService<S>
 where
     S: Service<Request<ReqBody>, Response = http::Response<ResBody>>,
+    ResBody: http_body::Body,
+    ResBody::Data: bytes::Buf,
+    ResBody::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
 {
     type Response = http::Response<crate::body::CompressionBody<ResBody>>;
     type Error = S::Error;
@@ -45,16 +58,56 @@ where
         self.inner.poll_ready(cx)
     }
 
-    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        let settings = &self.settings;
+        // Upstream middleware may have restricted the codecs for this request
+        let policy = req.extensions().get::<CompressionPolicy>().cloned();
+        let codecs = || {
+            settings
+                .preference
+                .iter()
+                .filter(|codec| policy.as_ref().is_none_or(|policy| policy.allows(*codec)))
+        };
+
         // Extract accepted codec from Accept-Encoding header
-        let accepted_codec = req
-            .headers()
-            .get(http::header::ACCEPT_ENCODING)
-            .and_then(|v| v.to_str().ok())
-            .and_then(Codec::from_accept_encoding);
+        let accepted_codec = AcceptEncoding::from_headers(req.headers())
+            .negotiate_among(codecs())
+            .codec();
+        let request = RequestInfo::from_request(&req);
+
+        // A shared dictionary the client has beats regular compression
+        let dictionary = dictionary::negotiate(&settings.dictionaries, req.headers(), codecs());
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            accept_encoding = ?req.headers().get(http::header::ACCEPT_ENCODING),
+            codec = ?accepted_codec,
+            dictionary_codec = ?dictionary.as_ref().map(|(codec, _)| codec),
+            policy = ?policy,
+            "negotiated response compression"
+        );
+
+        // Let the inner service validate the tags of the uncompressed representation
+        let accepted_coding = match &dictionary {
+            Some((codec, _)) => codec.dictionary_encoding(),
+            None => accepted_codec.map(|codec| codec.content_encoding()),
+        };
+        let validated = settings.etag.normalize_request(
+            req.headers_mut(),
+            &settings.preference,
+            accepted_coding,
+        );
 
         let inner = self.inner.call(req);
 
-        ResponseFuture::new(inner, accepted_codec, self.min_size)
+        ResponseFuture::new(
+            inner,
+            accepted_codec,
+            validated,
+            dictionary,
+            request,
+            policy,
+            self.settings.clone(),
+        )
     }
 }
@@ -0,0 +1,77 @@
+use crate::breach::BreachMitigation;
+use crate::cache::CompressionCache;
+use crate::codec::{CodecLevels, CodecPreference};
+use crate::dictionary::Dictionary;
+use crate::etag::ETagMode;
+use crate::layer::DEFAULT_MIN_SIZE;
+use crate::metrics::CompressionObserver;
+use crate::predicate::{DefaultPredicate, Predicate};
+use std::fmt;
+use std::sync::Arc;
+
+/// Configuration shared by the layer, its services and their response futures.
+#[derive(Clone)]
+pub(crate) struct Settings {
+    /// Compression level used for each codec.
+    pub(crate) levels: CodecLevels,
+    /// Enabled codecs in order of preference.
+    pub(crate) preference: CodecPreference,
+    /// Built-in rules deciding whether a response is compressed.
+    pub(crate) rules: DefaultPredicate,
+    /// Additional user-supplied rule.
+    pub(crate) predicate: Option<Arc<dyn Predicate>>,
+    /// Whether bodies of unknown length are buffered up to the minimum size
+    /// before deciding whether to compress them.
+    pub(crate) buffer_unknown_size: bool,
+    /// How the `ETag` of compressed responses is rewritten.
+    pub(crate) etag: ETagMode,
+    /// Cache of compressed bodies.
+    pub(crate) cache: Option<CompressionCache>,
+    /// Shared dictionaries for dictionary-compressed responses.
+    pub(crate) dictionaries: Vec<Arc<Dictionary>>,
+    /// Mitigations against BREACH.
+    pub(crate) breach: Option<BreachMitigation>,
+    /// Observer of compressed bodies.
+    pub(crate) observer: Option<Arc<dyn CompressionObserver>>,
+}
+
+impl Settings {
+    /// Creates default settings with the given minimum body size.
+    pub(crate) fn new(min_size: usize) -> Self {
+        Self {
+            levels: CodecLevels::default(),
+            preference: CodecPreference::default(),
+            rules: DefaultPredicate::new(min_size),
+            predicate: None,
+            buffer_unknown_size: false,
+            etag: ETagMode::default(),
+            cache: None,
+            dictionaries: Vec::new(),
+            breach: None,
+            observer: None,
+        }
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::new(DEFAULT_MIN_SIZE)
+    }
+}
+
+impl fmt::Debug for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Settings")
+            .field("levels", &self.levels)
+            .field("preference", &self.preference)
+            .field("rules", &self.rules)
+            .field("predicate", &self.predicate.as_ref().map(|_| ".."))
+            .field("buffer_unknown_size", &self.buffer_unknown_size)
+            .field("etag", &self.etag)
+            .field("cache", &self.cache)
+            .field("dictionaries", &self.dictionaries)
+            .field("breach", &self.breach)
+            .field("observer", &self.observer.as_ref().map(|_| ".."))
+            .finish()
+    }
+}